@@ -0,0 +1,28 @@
+/// Pins the calling thread to one of the cores the process is allowed to run
+/// on, chosen round-robin by `worker`. Returns `false` if pinning is not
+/// supported or failed.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(worker: usize) -> bool {
+    // SAFETY: `cpu_set_t` is plain data, and both calls only read or write
+    // the set we pass in.
+    unsafe {
+        let mut allowed: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut allowed) != 0 {
+            return false;
+        }
+        let cores: Vec<usize> = (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &allowed))
+            .collect();
+        if cores.is_empty() {
+            return false;
+        }
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cores[worker % cores.len()], &mut set);
+        libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) == 0
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_worker: usize) -> bool {
+    false
+}
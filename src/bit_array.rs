@@ -1,5 +1,5 @@
 use std::{
-    fmt::{Debug, Display},
+    fmt::Debug,
     hash::Hash,
     ops::{
         BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Shl, ShlAssign, Shr,
@@ -7,17 +7,168 @@ use std::{
     },
 };
 
-use num::{
-    NumCast, PrimInt, ToPrimitive as _, Unsigned,
-    traits::{ConstOne, ConstZero},
-};
 use typenum::ToInt;
 
+/// The storage behind a [`BitArray`]: a fixed number of bits supporting
+/// bitwise operations and shifts towards higher (`<<`) or lower (`>>`) bits.
+pub trait BitStore:
+    Copy
+    + PartialEq
+    + Eq
+    + Hash
+    + Debug
+    + Send
+    + Sync
+    + 'static
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitXor<Output = Self>
+    + Not<Output = Self>
+    + Shl<usize, Output = Self>
+    + Shr<usize, Output = Self>
+{
+    const ZERO: Self;
+    const ONE: Self;
+    fn count_ones(self) -> u32;
+    /// Fails if any bit above the lowest 64 is set.
+    fn to_u64(self) -> Option<u64>;
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_bit_store {
+    ($($t:ty),*) => {
+        $(impl BitStore for $t {
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            #[inline(always)]
+            fn count_ones(self) -> u32 {
+                <$t>::count_ones(self)
+            }
+            fn to_u64(self) -> Option<u64> {
+                u64::try_from(self).ok()
+            }
+            fn from_u64(value: u64) -> Self {
+                value as $t
+            }
+        })*
+    };
+}
+
+impl_bit_store!(u8, u16, u32, u64, u128);
+
+/// Bits stored across `K` words, for arrays too large for a primitive integer.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Words<const K: usize>([u64; K]);
+
+impl<const K: usize> Words<K> {
+    /// The lowest `bits` bits set.
+    pub const fn mask(bits: usize) -> Self {
+        let mut words = [0; K];
+        let mut i = 0;
+        while i < K {
+            words[i] = if bits >= (i + 1) * 64 {
+                u64::MAX
+            } else if bits > i * 64 {
+                u64::MAX >> ((i + 1) * 64 - bits)
+            } else {
+                0
+            };
+            i += 1;
+        }
+        Words(words)
+    }
+}
+
+impl<const K: usize> BitStore for Words<K> {
+    const ZERO: Self = Words([0; K]);
+    const ONE: Self = Words::mask(1);
+    fn count_ones(self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+    fn to_u64(self) -> Option<u64> {
+        self.0[1..]
+            .iter()
+            .all(|&word| word == 0)
+            .then_some(self.0[0])
+    }
+    fn from_u64(value: u64) -> Self {
+        let mut words = [0; K];
+        words[0] = value;
+        Words(words)
+    }
+}
+
+impl<const K: usize> BitAnd for Words<K> {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Words(std::array::from_fn(|i| self.0[i] & rhs.0[i]))
+    }
+}
+
+impl<const K: usize> BitOr for Words<K> {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Words(std::array::from_fn(|i| self.0[i] | rhs.0[i]))
+    }
+}
+
+impl<const K: usize> BitXor for Words<K> {
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        Words(std::array::from_fn(|i| self.0[i] ^ rhs.0[i]))
+    }
+}
+
+impl<const K: usize> Not for Words<K> {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        Words(self.0.map(|word| !word))
+    }
+}
+
+impl<const K: usize> Shl<usize> for Words<K> {
+    type Output = Self;
+
+    fn shl(self, rhs: usize) -> Self::Output {
+        let (words, bits) = (rhs / 64, rhs % 64);
+        Words(std::array::from_fn(|i| {
+            let word = |j: usize| if j < K { self.0[j] } else { 0 };
+            match i.checked_sub(words) {
+                Some(j) if bits == 0 => word(j),
+                Some(0) => word(0) << bits,
+                Some(j) => (word(j) << bits) | (word(j - 1) >> (64 - bits)),
+                None => 0,
+            }
+        }))
+    }
+}
+
+impl<const K: usize> Shr<usize> for Words<K> {
+    type Output = Self;
+
+    fn shr(self, rhs: usize) -> Self::Output {
+        let (words, bits) = (rhs / 64, rhs % 64);
+        Words(std::array::from_fn(|i| {
+            let word = |j: usize| if j < K { self.0[j] } else { 0 };
+            let j = i + words;
+            if bits == 0 {
+                word(j)
+            } else {
+                (word(j) >> bits) | (word(j + 1) << (64 - bits))
+            }
+        }))
+    }
+}
+
 macro_rules! impl_bit_array_sizes {
     ($($($n:ident),*: $t:ty,)*) => {
         $($(impl BitArraySize for typenum::$n {
             type T = $t;
-            const MASK: Self::T = Self::T::MAX >> (Self::T::BITS - <typenum::$n as ToInt<u32>>::INT);
+            const MASK: Self::T = <$t>::MAX >> (<$t>::BITS - <typenum::$n as ToInt<u32>>::INT);
         })*)*
     };
 }
@@ -27,6 +178,21 @@ impl_bit_array_sizes! {
     U9,U10,U11,U12,U13,U14,U15,U16: u16,
     U17,U18,U19,U20,U21,U22,U23,U24,U25,U26,U27,U28,U29,U30,U31,U32: u32,
     U33,U34,U35,U36,U37,U38,U39,U40,U41,U42,U43,U44,U45,U46,U47,U48,U49,U50,U51,U52,U53,U54,U55,U56,U57,U58,U59,U60,U61,U62,U63,U64: u64,
+    U65,U66,U67,U68,U69,U70,U71,U72,U73,U74,U75,U76,U77,U78,U79,U80,U81,U82,U83,U84,U85,U86,U87,U88,U89,U90,U91,U92,U93,U94,U95,U96,U97,U98,U99,U100,U101,U102,U103,U104,U105,U106,U107,U108,U109,U110,U111,U112,U113,U114,U115,U116,U117,U118,U119,U120,U121,U122,U123,U124,U125,U126,U127,U128: u128,
+}
+
+macro_rules! impl_multi_word_sizes {
+    ($($($n:ident),*: $k:literal,)*) => {
+        $($(impl BitArraySize for typenum::$n {
+            type T = Words<$k>;
+            const MASK: Self::T = Words::mask(<typenum::$n as ToInt<usize>>::INT);
+        })*)*
+    };
+}
+
+impl_multi_word_sizes! {
+    U129,U130,U131,U132,U133,U134,U135,U136,U137,U138,U139,U140,U141,U142,U143,U144,U145,U146,U147,U148,U149,U150,U151,U152,U153,U154,U155,U156,U157,U158,U159,U160,U161,U162,U163,U164,U165,U166,U167,U168,U169,U170,U171,U172,U173,U174,U175,U176,U177,U178,U179,U180,U181,U182,U183,U184,U185,U186,U187,U188,U189,U190,U191,U192: 3,
+    U193,U194,U195,U196,U197,U198,U199,U200,U201,U202,U203,U204,U205,U206,U207,U208,U209,U210,U211,U212,U213,U214,U215,U216,U217,U218,U219,U220,U221,U222,U223,U224,U225,U226,U227,U228,U229,U230,U231,U232,U233,U234,U235,U236,U237,U238,U239,U240,U241,U242,U243,U244,U245,U246,U247,U248,U249,U250,U251,U252,U253,U254,U255,U256: 4,
 }
 
 pub trait BitArraySize:
@@ -42,7 +208,7 @@ pub trait BitArraySize:
     + Sync
     + 'static
 {
-    type T: PrimInt + Unsigned + Hash + ConstZero + ConstOne + Display + Send + Sync + 'static;
+    type T: BitStore;
     const MASK: Self::T;
 }
 
@@ -75,15 +241,11 @@ impl<N: BitArraySize> BitArray<N> {
         self.0.count_ones()
     }
 
-    pub fn to_u64(self) -> u64 {
-        self.0.to_u64().expect("BitArray size exceeds u64 range")
-    }
-
     pub fn from_u64(value: u64) -> Self {
-        let value = <N::T as NumCast>::from(value).expect("Invalid BitArray size");
+        let value = N::T::from_u64(value);
         debug_assert!(
-            value <= N::MASK,
-            "Value exceeds BitArray size: {} > {}",
+            value & !N::MASK == N::T::ZERO,
+            "Value exceeds BitArray size: {:?} > {:?}",
             value,
             N::MASK
         );
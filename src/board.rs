@@ -1,8 +1,20 @@
-use std::fmt::Debug;
+use std::{
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+};
 
 use bitvec::{slice::BitSlice, vec::BitVec};
+use smallvec::SmallVec;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+use crate::symmetry::Symmetry;
+
+/// A finite pattern of live cells.
+///
+/// Boards are compared, ordered and hashed by their live cells alone, cropped
+/// to their bounding box, so the same pattern with different padding or at a
+/// different offset is the same board. All empty boards are equal.
+#[derive(Clone)]
 pub struct Board {
     bits: BitVec,
     stride: usize,
@@ -22,6 +34,40 @@ impl Debug for Board {
     }
 }
 
+impl PartialEq for Board {
+    fn eq(&self, other: &Self) -> bool {
+        let (width, rows) = self.tight_rows();
+        let (other_width, other_rows) = other.tight_rows();
+        width == other_width && rows.eq(other_rows)
+    }
+}
+
+impl Eq for Board {}
+
+impl PartialOrd for Board {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Board {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (width, rows) = self.tight_rows();
+        let (other_width, other_rows) = other.tight_rows();
+        width.cmp(&other_width).then_with(|| rows.cmp(other_rows))
+    }
+}
+
+impl Hash for Board {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let (width, rows) = self.tight_rows();
+        width.hash(state);
+        for row in rows {
+            row.hash(state);
+        }
+    }
+}
+
 impl Board {
     pub fn new(bits: BitVec, stride: usize) -> Self {
         debug_assert!(bits.len() % stride == 0, "Invalid stride for given bits");
@@ -30,26 +76,25 @@ impl Board {
     fn iter_rows(&self) -> impl Iterator<Item = &BitSlice> {
         self.bits.chunks(self.stride)
     }
-    pub fn load(path: &str) -> Result<Vec<Self>, std::io::Error> {
-        let content = std::fs::read_to_string(path)?;
-        let mut result = Vec::new();
-        for item in content.split("\n\n") {
-            let mut board = BitVec::new();
-            let mut stride = 0;
-            for line in item.lines() {
-                board.extend(line.chars().map(|c| c == '#'));
-                if stride == 0 {
-                    stride = line.len();
-                } else {
-                    assert!(
-                        line.len() == stride,
-                        "Inconsistent line length in board file"
-                    );
-                }
+    pub fn parse(item: &str) -> Self {
+        let mut board = BitVec::new();
+        let mut stride = 0;
+        for line in item.lines() {
+            board.extend(line.chars().map(|c| c == '#'));
+            if stride == 0 {
+                stride = line.len();
+            } else {
+                assert!(
+                    line.len() == stride,
+                    "Inconsistent line length in board file"
+                );
             }
-            result.push(Board::new(board, stride));
         }
-        Ok(result)
+        Board::new(board, stride)
+    }
+    pub fn load(path: &str) -> Result<Vec<Self>, std::io::Error> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(content.split("\n\n").map(Board::parse).collect())
     }
     pub fn width(&self) -> usize {
         self.stride
@@ -104,7 +149,155 @@ impl Board {
             false
         }
     }
+    /// Copies the `width` by `height` region starting at (`x0`, `y0`), treating
+    /// cells outside the board as dead.
+    pub fn crop(&self, x0: isize, y0: isize, width: usize, height: usize) -> Self {
+        let mut bits = BitVec::with_capacity(width * height);
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                bits.push(self.get((x0 + x) as usize, (y0 + y) as usize));
+            }
+        }
+        Board::new(bits, width)
+    }
+    /// Sets every cell which is live in `other`, placed at (`x0`, `y0`).
+    pub fn paste(&mut self, other: &Board, x0: usize, y0: usize) {
+        for y in 0..other.height() {
+            for x in 0..other.width() {
+                if other.get(x, y) {
+                    self.bits.set((y0 + y) * self.stride + x0 + x, true);
+                }
+            }
+        }
+    }
+    /// Groups the live cells into clusters, where two live cells belong to the
+    /// same cluster if they are fewer than `separation` cells apart in both
+    /// directions. Returns the bounding box `(x0, y0, x1, y1)` of each
+    /// cluster, inclusive.
+    pub fn components(&self, separation: usize) -> Vec<(usize, usize, usize, usize)> {
+        let cells: Vec<(usize, usize)> = self
+            .bits
+            .iter_ones()
+            .map(|i| (i % self.stride, i / self.stride))
+            .collect();
+        let mut parent: Vec<usize> = (0..cells.len()).collect();
+        fn root(parent: &mut [usize], mut i: usize) -> usize {
+            while parent[i] != i {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for i in 0..cells.len() {
+            for j in 0..i {
+                let (a, b) = (cells[i], cells[j]);
+                if a.0.abs_diff(b.0) < separation && a.1.abs_diff(b.1) < separation {
+                    let (ri, rj) = (root(&mut parent, i), root(&mut parent, j));
+                    parent[ri] = rj;
+                }
+            }
+        }
+        let mut boxes: Vec<(usize, (usize, usize, usize, usize))> = Vec::new();
+        for (i, &(x, y)) in cells.iter().enumerate() {
+            let r = root(&mut parent, i);
+            match boxes.iter_mut().find(|(root, _)| *root == r) {
+                Some((_, b)) => *b = (b.0.min(x), b.1.min(y), b.2.max(x), b.3.max(y)),
+                None => boxes.push((r, (x, y, x, y))),
+            }
+        }
+        boxes.into_iter().map(|(_, b)| b).collect()
+    }
+    /// The bounding box `(x0, y0, x1, y1)` of the live cells, inclusive, or
+    /// `None` if there are none.
+    pub fn bounding_box(&self) -> Option<(usize, usize, usize, usize)> {
+        let y0 = self.bits.first_one()? / self.stride;
+        let y1 = self.bits.last_one()? / self.stride;
+        let x0 = self.iter_rows().filter_map(|row| row.first_one()).min()?;
+        let x1 = self.iter_rows().filter_map(|row| row.last_one()).max()?;
+        Some((x0, y0, x1, y1))
+    }
+    /// The width of the bounding box of the live cells, and each of its rows.
+    fn tight_rows(&self) -> (usize, impl Iterator<Item = &BitSlice>) {
+        let (x0, y0, x1, y1) = self.bounding_box().unwrap_or((1, 1, 0, 0));
+        let rows = (y0..=y1).map(move |y| &self.bits[y * self.stride + x0..=y * self.stride + x1]);
+        (x1 + 1 - x0, rows)
+    }
+    /// Crops the board to the bounding box of its live cells. An empty board
+    /// becomes a single dead cell.
+    pub fn tight(&self) -> Self {
+        match self.bounding_box() {
+            Some((x0, y0, x1, y1)) => self.crop(x0 as isize, y0 as isize, x1 - x0 + 1, y1 - y0 + 1),
+            None => self.crop(0, 0, 1, 1),
+        }
+    }
+    pub fn transform(&self, symmetry: Symmetry) -> Self {
+        let (width, height) = (self.width(), self.height());
+        let stride = if symmetry.swaps_axes() { height } else { width };
+        let mut bits = BitVec::repeat(false, self.bits.len());
+        for i in self.bits.iter_ones() {
+            let (x, y) = symmetry.apply(i % width, i / width, width, height);
+            bits.set(y * stride + x, true);
+        }
+        Board::new(bits, stride)
+    }
+    /// The smallest of the tightly cropped images of the board under each
+    /// symmetry, shared by every board in the same orbit.
+    pub fn canonical(&self) -> Self {
+        let tight = self.tight();
+        Symmetry::ALL
+            .into_iter()
+            .map(|symmetry| tight.transform(symmetry))
+            .min()
+            .expect("There is always a symmetry")
+    }
+    pub fn is_symmetric(&self, symmetry: Symmetry) -> bool {
+        self.transform(symmetry) == *self
+    }
+    /// The symmetries other than the identity which leave the live cells
+    /// unchanged.
+    pub fn symmetries(&self) -> SmallVec<[Symmetry; 8]> {
+        Symmetry::ALL[1..]
+            .iter()
+            .copied()
+            .filter(|&symmetry| self.is_symmetric(symmetry))
+            .collect()
+    }
+    /// Packs the board into rows of 64-bit words, with `padding` dead cells
+    /// added on every side.
+    fn pack(&self, padding: usize) -> PackedBoard {
+        let width = self.width() + 2 * padding;
+        let height = self.height() + 2 * padding;
+        let words_per_row = width.div_ceil(64);
+        let mut words = vec![0u64; words_per_row * height];
+        for i in self.bits.iter_ones() {
+            let x = i % self.stride + padding;
+            let y = i / self.stride + padding;
+            words[y * words_per_row + x / 64] |= 1 << (x % 64);
+        }
+        PackedBoard {
+            width,
+            height,
+            words_per_row,
+            words,
+        }
+    }
     pub fn simulate(&self) -> Self {
+        self.simulate_n(1)
+    }
+    /// Advances the board by `generations` steps. The result is large enough
+    /// to hold every cell the pattern could reach, then trimmed.
+    pub fn simulate_n(&self, generations: usize) -> Self {
+        let mut packed = self.pack(generations);
+        for _ in 0..generations {
+            packed.step();
+        }
+        let mut result = packed.unpack();
+        result.trim();
+        result
+    }
+    /// Cell by cell version of [`Board::simulate`], used to check it.
+    #[cfg(test)]
+    fn simulate_naive(&self) -> Self {
         let mut new_board = BitVec::new();
         for y in 0..self.height() + 2 {
             for x in 0..self.width() + 2 {
@@ -135,3 +328,101 @@ impl Board {
         result
     }
 }
+
+/// A board stored as rows of 64-bit words, so that a generation can be
+/// computed for 64 cells at a time.
+struct PackedBoard {
+    width: usize,
+    height: usize,
+    words_per_row: usize,
+    words: Vec<u64>,
+}
+
+impl PackedBoard {
+    fn row(&self, y: usize) -> &[u64] {
+        &self.words[y * self.words_per_row..(y + 1) * self.words_per_row]
+    }
+    /// The row's cells shifted so that each bit holds its left and right
+    /// neighbour respectively.
+    fn shifted(row: &[u64], i: usize) -> (u64, u64) {
+        let previous = if i > 0 { row[i - 1] >> 63 } else { 0 };
+        let next = row.get(i + 1).map_or(0, |word| word << 63);
+        ((row[i] << 1) | previous, (row[i] >> 1) | next)
+    }
+    /// Advances one generation. Cells outside the board are treated as dead.
+    fn step(&mut self) {
+        fn add3(a: u64, b: u64, c: u64) -> (u64, u64) {
+            (a ^ b ^ c, (a & b) | (c & (a ^ b)))
+        }
+        let empty = vec![0u64; self.words_per_row];
+        let last_mask = match self.width % 64 {
+            0 => !0,
+            bits => (1 << bits) - 1,
+        };
+        let mut words = vec![0u64; self.words.len()];
+        for y in 0..self.height {
+            let above = if y > 0 { self.row(y - 1) } else { &empty };
+            let below = if y + 1 < self.height {
+                self.row(y + 1)
+            } else {
+                &empty
+            };
+            let center = self.row(y);
+            for i in 0..self.words_per_row {
+                let (above_left, above_right) = Self::shifted(above, i);
+                let (center_left, center_right) = Self::shifted(center, i);
+                let (below_left, below_right) = Self::shifted(below, i);
+                // Bit-sliced sum of the eight neighbours: `ones` holds the
+                // count's lowest bit, and the four carries each count two.
+                let (above_ones, above_twos) = add3(above_left, above[i], above_right);
+                let (below_ones, below_twos) = add3(below_left, below[i], below_right);
+                let (center_ones, center_twos) =
+                    (center_left ^ center_right, center_left & center_right);
+                let (ones, ones_twos) = add3(above_ones, below_ones, center_ones);
+                let (low, low_carry) = (above_twos ^ below_twos, above_twos & below_twos);
+                let (high, high_carry) = (center_twos ^ ones_twos, center_twos & ones_twos);
+                // Exactly one carry means a count of two or three.
+                let one_two = (low ^ high) & !(low_carry | high_carry | (low & high));
+                let mut word = one_two & (ones | center[i]);
+                if i + 1 == self.words_per_row {
+                    word &= last_mask;
+                }
+                words[y * self.words_per_row + i] = word;
+            }
+        }
+        self.words = words;
+    }
+    fn unpack(&self) -> Board {
+        let mut bits = BitVec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            let row = self.row(y);
+            bits.extend((0..self.width).map(|x| row[x / 64] >> (x % 64) & 1 == 1));
+        }
+        Board::new(bits, self.width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn simulate_matches_naive() {
+        let mut rng = Rng::new(0);
+        // Widths past 64 cover rows spanning several words.
+        for (width, height) in [(1, 1), (5, 7), (63, 3), (64, 9), (65, 4), (130, 20)] {
+            for density in [4, 2, 1] {
+                let bits = (0..width * height)
+                    .map(|_| rng.below(density) == 0)
+                    .collect();
+                let mut board = Board::new(bits, width);
+                for _ in 0..4 {
+                    let next = board.simulate_naive();
+                    assert_eq!(board.simulate(), next);
+                    board = next;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::{fmt::Write as _, io, sync::Arc};
+
+use crate::board::Board;
+
+/// A board found by the search, linked to the board it is a predecessor of.
+/// Following the links leads back to the target the search started from.
+#[derive(Debug)]
+pub struct ChainNode {
+    pub board: Board,
+    /// Generations between `board` and its target.
+    pub step: usize,
+    pub parent: Option<Arc<ChainNode>>,
+    /// The board the chain ends with, shared by every node of the chain.
+    target: Arc<Board>,
+}
+
+impl ChainNode {
+    /// A board without a recorded parent: a target, or a board loaded from a
+    /// snapshot, which does not keep parents.
+    pub fn root(board: Board, step: usize) -> Arc<Self> {
+        let target = Arc::new(board.simulate_n(step));
+        Arc::new(ChainNode {
+            board,
+            step,
+            parent: None,
+            target,
+        })
+    }
+    /// A predecessor of `parent`.
+    pub fn child(parent: &Arc<Self>, board: Board) -> Arc<Self> {
+        Arc::new(ChainNode {
+            board,
+            step: parent.step + 1,
+            parent: Some(parent.clone()),
+            target: parent.target.clone(),
+        })
+    }
+    /// The board this node's chain ends with.
+    pub fn target(&self) -> &Arc<Board> {
+        &self.target
+    }
+    /// `board` followed by every board up to its target. Where a parent was
+    /// not recorded the chain is completed by simulating forward instead.
+    pub fn chain(&self) -> Vec<Board> {
+        let mut chain = vec![self.board.clone()];
+        let mut node = self;
+        while let Some(parent) = &node.parent {
+            chain.push(parent.board.clone());
+            node = parent;
+        }
+        for _ in 0..node.step {
+            chain.push(chain.last().unwrap().simulate());
+        }
+        chain
+    }
+}
+
+/// Formats a chain as its boards followed by a line with its length in steps.
+pub fn format_chain(chain: &[Board]) -> String {
+    let mut result = String::new();
+    for board in chain {
+        writeln!(result, "{:?}", board).unwrap();
+    }
+    writeln!(result, "--------- {} ----------\n", chain.len() - 1).unwrap();
+    result
+}
+
+pub fn save_chains(path: &str, chains: &[Vec<Board>]) -> Result<(), io::Error> {
+    std::fs::write(
+        path,
+        chains
+            .iter()
+            .map(|chain| format_chain(chain))
+            .collect::<String>(),
+    )
+}
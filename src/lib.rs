@@ -0,0 +1,27 @@
+//! Searches backwards through Conway's Game of Life for boards which lead to a
+//! given target.
+//!
+//! A [`WorkQueue`] runs the search on worker threads, configured by a
+//! [`WorkQueueConfig`], and reports what it finds to a [`SearchObserver`],
+//! including periodic [`Progress`].
+
+mod affinity;
+pub mod bit_array;
+pub mod board;
+pub mod chain;
+pub mod miniboard;
+mod nogoods;
+pub mod observer;
+mod priority_index;
+pub mod progress;
+pub mod reverse_index;
+mod rng;
+pub mod snapshot;
+pub mod state;
+pub mod symmetry;
+pub mod work_queue;
+
+pub use board::Board;
+pub use observer::SearchObserver;
+pub use progress::Progress;
+pub use work_queue::{WorkQueue, WorkQueueConfig};
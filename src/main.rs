@@ -1,20 +1,251 @@
-use crate::{board::Board, work_queue::WorkQueue};
+use std::{
+    str::FromStr,
+    sync::{Arc, atomic::AtomicBool},
+    time::Duration,
+};
 
-mod bit_array;
-mod board;
-mod miniboard;
-mod reverse_index;
-mod state;
-mod work_queue;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use typenum::{U4, U5, U6};
 
-type N = typenum::U4;
+use reverse_gol::{
+    Board, WorkQueue, WorkQueueConfig, chain,
+    miniboard::{B, MacroDimension, MacroboardSize},
+    observer::ConsoleObserver,
+    reverse_index::ReverseIndex,
+    snapshot::Snapshot,
+    state::RestartPolicy,
+    symmetry::SymmetryMode,
+};
+
+/// Tile shapes, as width by height, that the search is compiled for.
+const TILES: [(usize, usize); 6] = [(4, 4), (4, 5), (5, 4), (5, 5), (4, 6), (6, 4)];
+const DEFAULT_TILE: (usize, usize) = (4, 4);
 const NUM_STEPS: usize = 16;
-const BUDGET_FACTOR: usize = 10000;
-const PARALLEL: bool = true;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+const LUBY_RESTART_UNIT: usize = 100;
+const GEOMETRIC_RESTART_INITIAL: usize = 100;
+const GEOMETRIC_RESTART_FACTOR: f64 = 1.5;
+
+const USAGE: &str = "\
+Usage: reverse-gol [options]
+    --threads <n>       Number of worker threads (default: one per core)
+    --reserve <n>       Cores to leave free when --threads is not given
+    --pin               Pin worker threads to cores
+    --deterministic     Single-threaded, reproducible search
+    --tile <w>x<h>      Tile shape: 4x4 (default), 4x5, 5x4, 5x5, 4x6 or 6x4
+    --decompose         Search well separated groups of cells independently
+    --canonical         Treat rotated and reflected copies of a board as seen
+    --symmetric <mode>  Require symmetric predecessors of symmetric boards:
+                        any, c2 (180 degree rotation) or d4 (all symmetries)
+    --seed <n>          Seed for randomized search (default: 0)
+    --randomize         Try each cell's options in a random order
+    --restarts <policy> Restart policy: never, luby or geometric
+    --output <path>     Write all boards found to <path> on exit
+    --snapshot <path>   Write a resumable snapshot to <path> on exit
+    --resume <path>     Resume from a snapshot instead of input.txt
+    --chains <path>     Write the chain from every board found to its target to
+                        <path> on exit
+    --progress <secs>   Seconds between progress reports, 0 to disable
+                        (default: 5)
+    --index-stats       Print statistics of the reverse index for the tile
+                        shape and exit
+    --segment <path>    Print the predecessors of the miniboard in <path>,
+                        which must be two cells smaller than the tile, and exit";
+
+#[derive(Default)]
+struct Options {
+    config: WorkQueueConfig,
+    tile: Option<(usize, usize)>,
+    output: Option<String>,
+    snapshot: Option<String>,
+    resume: Option<String>,
+    chains: Option<String>,
+    index_stats: bool,
+    segment: Option<String>,
+}
+
+fn parse_number<T: FromStr>(value: Option<String>) -> T {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| exit_with_usage())
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn parse_restarts(value: Option<String>) -> RestartPolicy {
+    match value.as_deref() {
+        Some("never") => RestartPolicy::Never,
+        Some("luby") => RestartPolicy::Luby {
+            unit: LUBY_RESTART_UNIT,
+        },
+        Some("geometric") => RestartPolicy::Geometric {
+            initial: GEOMETRIC_RESTART_INITIAL,
+            factor: GEOMETRIC_RESTART_FACTOR,
+        },
+        _ => exit_with_usage(),
+    }
+}
+
+fn parse_symmetry(value: Option<String>) -> SymmetryMode {
+    match value.as_deref() {
+        Some("any") => SymmetryMode::Any,
+        Some("c2") => SymmetryMode::C2,
+        Some("d4") => SymmetryMode::D4,
+        _ => exit_with_usage(),
+    }
+}
+
+fn parse_tile(value: Option<String>) -> Option<(usize, usize)> {
+    value
+        .as_deref()
+        .and_then(|v| v.split_once('x'))
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .filter(|tile| TILES.contains(tile))
+        .or_else(|| exit_with_usage())
+}
+
+fn parse_interval(value: Option<String>) -> Option<Duration> {
+    let seconds: f64 = parse_number(value);
+    if !seconds.is_finite() || seconds < 0.0 {
+        exit_with_usage();
+    }
+    (seconds > 0.0).then(|| Duration::from_secs_f64(seconds))
+}
+
+fn parse_path(value: Option<String>) -> Option<String> {
+    Some(value.unwrap_or_else(|| exit_with_usage()))
+}
+
+fn parse_options() -> Options {
+    let mut options = Options::default();
+    options.config.progress_interval = Some(PROGRESS_INTERVAL);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--threads" => options.config.threads = Some(parse_number(args.next())),
+            "--reserve" => options.config.reserved_cores = parse_number(args.next()),
+            "--pin" => options.config.pin_threads = true,
+            "--deterministic" => options.config.deterministic = true,
+            "--tile" => options.tile = parse_tile(args.next()),
+            "--decompose" => options.config.decompose = true,
+            "--canonical" => options.config.canonicalize = true,
+            "--symmetric" => options.config.symmetry = parse_symmetry(args.next()),
+            "--seed" => options.config.search.seed = parse_number(args.next()),
+            "--randomize" => options.config.search.randomize = true,
+            "--restarts" => options.config.search.restarts = parse_restarts(args.next()),
+            "--output" => options.output = parse_path(args.next()),
+            "--snapshot" => options.snapshot = parse_path(args.next()),
+            "--resume" => options.resume = parse_path(args.next()),
+            "--chains" => options.chains = parse_path(args.next()),
+            "--progress" => options.config.progress_interval = parse_interval(args.next()),
+            "--index-stats" => options.index_stats = true,
+            "--segment" => options.segment = parse_path(args.next()),
+            _ => exit_with_usage(),
+        }
+    }
+    options
+}
 
 fn main() {
-    let boards = Board::load("input.txt").expect("Failed to load board");
+    let options = parse_options();
+    match options.tile.unwrap_or(DEFAULT_TILE) {
+        (4, 4) => run::<U4, U4>(&options),
+        (4, 5) => run::<U4, U5>(&options),
+        (5, 4) => run::<U5, U4>(&options),
+        (5, 5) => run::<U5, U5>(&options),
+        (4, 6) => run::<U4, U6>(&options),
+        (6, 4) => run::<U6, U4>(&options),
+        _ => unreachable!("Tile shapes are checked when parsing options"),
+    }
+}
+
+fn run<W: MacroboardSize<H>, H: MacroDimension>(options: &Options) {
+    if options.index_stats || options.segment.is_some() {
+        inspect_index::<W, H>(options);
+        return;
+    }
+    let snapshot = match &options.resume {
+        Some(path) => Snapshot::load(path).expect("Failed to load snapshot"),
+        None => Snapshot::from_boards(Board::load("input.txt").expect("Failed to load board")),
+    };
+
+    // A second signal while shutting down exits immediately.
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone())
+            .expect("Failed to register signal handler");
+        signal_hook::flag::register(signal, shutdown.clone())
+            .expect("Failed to register signal handler");
+    }
+
+    search::<W, H>(snapshot, options, &shutdown);
+}
+
+fn inspect_index<W: MacroboardSize<H>, H: MacroDimension>(options: &Options) {
+    let index = ReverseIndex::<W, H>::new();
+    if options.index_stats {
+        println!("Reverse index for {}x{} tiles", W::INT, H::INT);
+        println!("{}", index.report());
+    }
+    if let Some(path) = &options.segment {
+        let board = Board::load(path)
+            .expect("Failed to load miniboard")
+            .remove(0);
+        let (width, height) = (W::INT - 2, H::INT - 2);
+        if (board.width(), board.height()) != (width, height) {
+            eprintln!("The miniboard must be {}x{}", width, height);
+            std::process::exit(2);
+        }
+        let mut miniboard = B::EMPTY;
+        for y in 0..height {
+            for x in 0..width {
+                miniboard.set(x, y, board.get(x, y));
+            }
+        }
+        let segment = index.segment(miniboard);
+        eprintln!("{} predecessors", segment.all().len());
+        for b in segment.all() {
+            println!("{:?}", b.to_board());
+        }
+    }
+}
+
+fn search<W: MacroboardSize<H>, H: MacroDimension>(
+    snapshot: Snapshot,
+    options: &Options,
+    shutdown: &AtomicBool,
+) {
+    let queue = WorkQueue::<W, H>::start(
+        snapshot,
+        NUM_STEPS,
+        &options.config,
+        Arc::new(ConsoleObserver::default()),
+    );
+    queue.wait(shutdown);
+    queue.print_best();
 
-    let queue = WorkQueue::<N>::start(boards, NUM_STEPS, PARALLEL);
-    queue.wait();
+    if let Some(path) = &options.chains {
+        let chains: Vec<_> = queue
+            .targets()
+            .iter()
+            .flat_map(|target| queue.chains_to(target))
+            .collect();
+        chain::save_chains(path, &chains).expect("Failed to write chains");
+    }
+    if options.output.is_some() || options.snapshot.is_some() {
+        let snapshot = queue.snapshot();
+        if let Some(path) = &options.output {
+            let seen = Snapshot {
+                queued: Vec::new(),
+                seen: snapshot.seen.clone(),
+            };
+            seen.save(path).expect("Failed to write seen boards");
+        }
+        if let Some(path) = &options.snapshot {
+            snapshot.save(path).expect("Failed to write snapshot");
+        }
+    }
 }
@@ -4,13 +4,17 @@ use std::{
     ops::{Mul, Sub},
 };
 
-use typenum::{Diff, Square, ToInt, U2};
+use typenum::{Diff, Prod, ToInt, U2};
 
-use crate::bit_array::{BitArray, BitArraySize};
+use crate::{
+    bit_array::{BitArray, BitArraySize, BitStore},
+    board::Board,
+    symmetry::Symmetry,
+};
 
-pub trait MiniboardSize:
+/// A tile width or height.
+pub trait Dimension:
     Sized
-    + Mul<Output: BitArraySize>
     + ToInt<usize>
     + Copy
     + Clone
@@ -25,50 +29,56 @@ pub trait MiniboardSize:
 {
 }
 impl<
-    N: Mul<Output: BitArraySize>
-        + ToInt<usize>
-        + Copy
-        + Clone
-        + PartialEq
-        + Eq
-        + Hash
-        + Default
-        + Debug
-        + Send
-        + Sync
-        + 'static,
-> MiniboardSize for N
+    N: ToInt<usize> + Copy + Clone + PartialEq + Eq + Hash + Default + Debug + Send + Sync + 'static,
+> Dimension for N
 {
 }
 
-pub trait MacroboardSize: MiniboardSize + Sub<U2, Output: MiniboardSize> {}
-impl<N: MiniboardSize + Sub<U2, Output: MiniboardSize>> MacroboardSize for N {}
+/// A tile width which, together with the height `H`, fits in a [`BitArray`].
+pub trait MiniboardSize<H: Dimension>: Dimension + Mul<H, Output: BitArraySize> {}
+impl<W: Dimension + Mul<H, Output: BitArraySize>, H: Dimension> MiniboardSize<H> for W {}
+
+/// A tile height large enough to have an inner tile after one step.
+pub trait MacroDimension: Dimension + Sub<U2, Output: Dimension> {}
+impl<N: Dimension + Sub<U2, Output: Dimension>> MacroDimension for N {}
+
+/// A tile width which, together with the height `H`, steps to a smaller tile.
+pub trait MacroboardSize<H: MacroDimension>:
+    MiniboardSize<H> + MacroDimension + Sub<U2, Output: MiniboardSize<Diff<H, U2>>>
+{
+}
+impl<
+    W: MiniboardSize<H> + MacroDimension + Sub<U2, Output: MiniboardSize<Diff<H, U2>>>,
+    H: MacroDimension,
+> MacroboardSize<H> for W
+{
+}
 
+/// A `W` by `H` tile of cells.
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
-pub struct B<N: MiniboardSize>(pub BitArray<Square<N>>);
+pub struct B<W: MiniboardSize<H>, H: Dimension>(pub BitArray<Prod<W, H>>);
 
-impl<N: MiniboardSize> B<N> {
+impl<W: MiniboardSize<H>, H: Dimension> B<W, H> {
     pub const EMPTY: Self = B(BitArray::ZERO);
 
-    fn h_mask() -> BitArray<Square<N>> {
-        !BitArray(
-            <BitArray<Square<N>>>::MAX.0
-                / (<BitArray<Square<N>>>::MAX.0 >> (N::INT * N::INT - N::INT)),
-        )
+    fn h_mask() -> BitArray<Prod<W, H>> {
+        !(0..H::INT).fold(BitArray::ZERO, |mask, y| {
+            mask | (BitArray::ONE >> (y * W::INT))
+        })
     }
 
-    fn v_mask() -> BitArray<Square<N>> {
-        BitArray((<BitArray<Square<N>>>::MAX.0 << N::INT) & <BitArray<Square<N>>>::MAX.0)
+    fn v_mask() -> BitArray<Prod<W, H>> {
+        BitArray((<BitArray<Prod<W, H>>>::MAX.0 << W::INT) & <BitArray<Prod<W, H>>>::MAX.0)
     }
 
     pub fn get(&self, x: usize, y: usize) -> bool {
-        debug_assert!(x < N::INT && y < N::INT);
-        self.0.get(y * N::INT + x)
+        debug_assert!(x < W::INT && y < H::INT);
+        self.0.get(y * W::INT + x)
     }
 
     pub fn set(&mut self, x: usize, y: usize, value: bool) {
-        debug_assert!(x < N::INT && y < N::INT);
-        self.0.set(y * N::INT + x, value);
+        debug_assert!(x < W::INT && y < H::INT);
+        self.0.set(y * W::INT + x, value);
     }
 
     pub fn shift_left(mut self, n: usize) -> Self {
@@ -86,32 +96,91 @@ impl<N: MiniboardSize> B<N> {
         self
     }
     pub fn shift_up(mut self, n: usize) -> Self {
-        self.0 <<= N::INT * n;
+        self.0 <<= W::INT * n;
         self
     }
     pub fn shift_down(mut self, n: usize) -> Self {
-        self.0 >>= N::INT * n;
+        self.0 >>= W::INT * n;
         self.0 &= Self::v_mask();
         self
     }
+    /// The tile's image under `symmetry`, about the tile's centre. Symmetries
+    /// which swap the axes only apply to square tiles.
+    pub fn transform(self, symmetry: Symmetry) -> Self {
+        debug_assert!(!symmetry.swaps_axes() || W::INT == H::INT);
+        let mut result = Self::EMPTY;
+        for y in 0..H::INT {
+            for x in 0..W::INT {
+                if self.get(x, y) {
+                    let (tx, ty) = symmetry.apply(x, y, W::INT, H::INT);
+                    result.set(tx, ty, true);
+                }
+            }
+        }
+        result
+    }
 
     #[inline(always)]
     pub fn live_count(self) -> u32 {
         self.0.count_ones()
     }
+
+    pub fn to_board(self) -> Board {
+        let bits = (0..H::INT)
+            .flat_map(|y| (0..W::INT).map(move |x| self.get(x, y)))
+            .collect();
+        Board::new(bits, W::INT)
+    }
 }
 
-impl<N: MiniboardSize> Debug for B<N> {
+impl<W: MiniboardSize<H>, H: Dimension> Debug for B<W, H> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "B{}{:?}", N::INT, self.0)
+        write!(f, "B{}x{}{:?}", W::INT, H::INT, self.0)
     }
 }
 
-impl<N: MacroboardSize> B<N> {
-    pub fn step(self) -> B<Diff<N, U2>> {
-        let mut result = B::<Diff<N, U2>>::EMPTY;
-        for y in 0..(N::INT - 2) {
-            for x in 0..(N::INT - 2) {
+impl<W: MacroboardSize<H>, H: MacroDimension> B<W, H> {
+    pub fn step(self) -> B<Diff<W, U2>, Diff<H, U2>> {
+        fn add3<M: BitArraySize>(
+            a: BitArray<M>,
+            b: BitArray<M>,
+            c: BitArray<M>,
+        ) -> (BitArray<M>, BitArray<M>) {
+            (a ^ b ^ c, (a & b) | (c & (a ^ b)))
+        }
+        // Each cell of the full board counts the neighbours around it. Only
+        // the inner cells have all of their neighbours, so only they are kept.
+        let above = self.shift_down(1);
+        let below = self.shift_up(1);
+        let (above_ones, above_twos) = add3(above.shift_right(1).0, above.0, above.shift_left(1).0);
+        let (below_ones, below_twos) = add3(below.shift_right(1).0, below.0, below.shift_left(1).0);
+        let (left, right) = (self.shift_right(1).0, self.shift_left(1).0);
+        let (center_ones, center_twos) = (left ^ right, left & right);
+        let (ones, ones_twos) = add3(above_ones, below_ones, center_ones);
+        let (low, low_carry) = (above_twos ^ below_twos, above_twos & below_twos);
+        let (high, high_carry) = (center_twos ^ ones_twos, center_twos & ones_twos);
+        // Exactly one carry means a count of two or three.
+        let one_two = (low ^ high) & !(low_carry | high_carry | (low & high));
+        let full = one_two & (ones | self.0);
+
+        let inner = W::INT - 2;
+        let one = <Prod<W, H> as BitArraySize>::T::ONE;
+        let row_mask = (0..inner).fold(BitStore::ZERO, |mask, x| mask | (one << x));
+        let mut result = B::<Diff<W, U2>, Diff<H, U2>>::EMPTY;
+        for y in 0..H::INT - 2 {
+            let row = (full.0 >> ((y + 1) * W::INT + 1)) & row_mask;
+            let row = BitStore::from_u64(row.to_u64().expect("Inner rows fit in 64 bits"));
+            result.0 |= BitArray(row) >> (y * inner);
+        }
+        result
+    }
+
+    /// Cell by cell version of [`B::step`], used to check it.
+    #[cfg(test)]
+    fn step_naive(self) -> B<Diff<W, U2>, Diff<H, U2>> {
+        let mut result = B::<Diff<W, U2>, Diff<H, U2>>::EMPTY;
+        for y in 0..(H::INT - 2) {
+            for x in 0..(W::INT - 2) {
                 let neighbor_count = self.get(x, y) as usize
                     + self.get(x, y + 1) as usize
                     + self.get(x, y + 2) as usize
@@ -130,3 +199,47 @@ impl<N: MacroboardSize> B<N> {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use typenum::{U4, U5, U6};
+
+    use super::*;
+
+    fn check_step<W: MacroboardSize<H>, H: MacroDimension>() {
+        for i in 0..1u64 << <Prod<W, H>>::INT {
+            let b: B<W, H> = B(BitArray::from_u64(i));
+            assert_eq!(b.step(), b.step_naive(), "{:?}", b);
+        }
+    }
+
+    #[test]
+    fn step_matches_naive_4x4() {
+        check_step::<U4, U4>();
+    }
+
+    #[test]
+    fn step_matches_naive_4x5() {
+        check_step::<U4, U5>();
+    }
+
+    #[test]
+    fn step_matches_naive_5x4() {
+        check_step::<U5, U4>();
+    }
+
+    #[test]
+    fn step_matches_naive_5x5() {
+        check_step::<U5, U5>();
+    }
+
+    #[test]
+    fn step_matches_naive_4x6() {
+        check_step::<U4, U6>();
+    }
+
+    #[test]
+    fn step_matches_naive_6x4() {
+        check_step::<U6, U4>();
+    }
+}
@@ -0,0 +1,62 @@
+use metrohash::MetroHashMap;
+use smallvec::SmallVec;
+
+use crate::miniboard::{B, MacroDimension, MacroboardSize};
+
+/// A cell index and the macroboard assigned to it.
+pub type Assignment<W, H> = (usize, B<W, H>);
+
+/// A set of cell assignments which cannot all hold in any solution.
+pub type Nogood<W, H> = SmallVec<[Assignment<W, H>; 8]>;
+
+/// Nogoods learned by a [`crate::state::State`], indexed by each of their
+/// assignments so that the ones relevant to a new assignment can be found
+/// quickly.
+#[derive(Debug)]
+pub struct NogoodDb<W: MacroboardSize<H>, H: MacroDimension> {
+    nogoods: Vec<Nogood<W, H>>,
+    watches: MetroHashMap<Assignment<W, H>, SmallVec<[u32; 4]>>,
+}
+
+/// Upper bound on the nogoods kept per state, to bound memory use.
+const MAX_NOGOODS: usize = 100_000;
+
+impl<W: MacroboardSize<H>, H: MacroDimension> Default for NogoodDb<W, H> {
+    fn default() -> Self {
+        Self {
+            nogoods: Vec::new(),
+            watches: MetroHashMap::default(),
+        }
+    }
+}
+
+impl<W: MacroboardSize<H>, H: MacroDimension> NogoodDb<W, H> {
+    pub fn add(&mut self, nogood: Nogood<W, H>) {
+        if self.nogoods.len() >= MAX_NOGOODS {
+            return;
+        }
+        let id = self.nogoods.len() as u32;
+        for &literal in &nogood {
+            self.watches.entry(literal).or_default().push(id);
+        }
+        self.nogoods.push(nogood);
+    }
+    /// Finds a nogood that would be violated by assigning `b` to `idx`, given
+    /// which assignments currently hold.
+    pub fn violated(
+        &self,
+        idx: usize,
+        b: B<W, H>,
+        holds: impl Fn(usize, B<W, H>) -> bool,
+    ) -> Option<&Nogood<W, H>> {
+        self.watches
+            .get(&(idx, b))?
+            .iter()
+            .map(|&id| &self.nogoods[id as usize])
+            .find(|nogood| {
+                nogood
+                    .iter()
+                    .all(|&(other, value)| other == idx || holds(other, value))
+            })
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::Mutex;
+
+use crate::{
+    board::Board,
+    chain::format_chain,
+    progress::{Progress, ProgressReporter},
+};
+
+/// Receives the events of a running [`crate::work_queue::WorkQueue`]. Every
+/// hook does nothing by default. Hooks other than `on_stats` and `on_finish`
+/// are called from worker threads, so they should return quickly.
+pub trait SearchObserver: Send + Sync {
+    /// A board not seen before was found `step` steps before the target.
+    fn on_solution(&self, _step: usize, _board: &Board) {}
+    /// A board was found further from the target than any before it. `chain`
+    /// runs from that board to the target, one generation at a time. Bests
+    /// arrive in order of step: one found while a later best is reported is
+    /// dropped. The queue's state is not locked, so this may call back into
+    /// the queue.
+    fn on_new_best(&self, _step: usize, _chain: &[Board]) {}
+    /// Every predecessor of a work item at `step` has been found.
+    fn on_item_completed(&self, _step: usize) {}
+    /// Called periodically while the search runs, as often as
+    /// [`crate::work_queue::WorkQueueConfig::progress_interval`] asks.
+    fn on_stats(&self, _progress: &Progress) {}
+    /// The search has stopped.
+    fn on_finish(&self) {}
+}
+
+/// Prints each new best chain to stdout and reports progress on stderr,
+/// keeping the status line clear of the chains on a terminal.
+#[derive(Default)]
+pub struct ConsoleObserver {
+    reporter: Mutex<ProgressReporter>,
+}
+
+impl SearchObserver for ConsoleObserver {
+    fn on_new_best(&self, _step: usize, chain: &[Board]) {
+        self.reporter
+            .lock()
+            .unwrap()
+            .print_above(&format_chain(chain));
+    }
+    fn on_stats(&self, progress: &Progress) {
+        self.reporter.lock().unwrap().report(progress);
+    }
+    fn on_finish(&self) {
+        self.reporter.lock().unwrap().finish();
+    }
+}
+
+pub fn print_chain(chain: &[Board]) {
+    print!("{}", format_chain(chain));
+}
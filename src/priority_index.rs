@@ -0,0 +1,69 @@
+/// A binary min-heap over the indices `0..len`, keyed by a priority that can
+/// be changed in place. Ties are broken by the lower index, so `min` agrees
+/// with a linear scan for the first minimum.
+#[derive(Debug, Default)]
+pub struct PriorityIndex {
+    heap: Vec<usize>,
+    positions: Vec<usize>,
+    priorities: Vec<usize>,
+}
+
+impl PriorityIndex {
+    pub fn new(priorities: Vec<usize>) -> Self {
+        let mut result = PriorityIndex {
+            heap: (0..priorities.len()).collect(),
+            positions: (0..priorities.len()).collect(),
+            priorities,
+        };
+        for pos in (0..result.heap.len() / 2).rev() {
+            result.sift_down(pos);
+        }
+        result
+    }
+    /// Returns the index with the lowest priority, and that priority.
+    pub fn min(&self) -> Option<(usize, usize)> {
+        self.heap.first().map(|&idx| (idx, self.priorities[idx]))
+    }
+    pub fn set(&mut self, idx: usize, priority: usize) {
+        let old = self.priorities[idx];
+        self.priorities[idx] = priority;
+        if priority < old {
+            self.sift_up(self.positions[idx]);
+        } else if priority > old {
+            self.sift_down(self.positions[idx]);
+        }
+    }
+    fn less(&self, a: usize, b: usize) -> bool {
+        (self.priorities[a], a) < (self.priorities[b], b)
+    }
+    fn swap(&mut self, i: usize, j: usize) {
+        self.heap.swap(i, j);
+        self.positions[self.heap[i]] = i;
+        self.positions[self.heap[j]] = j;
+    }
+    fn sift_up(&mut self, mut pos: usize) {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if !self.less(self.heap[pos], self.heap[parent]) {
+                break;
+            }
+            self.swap(pos, parent);
+            pos = parent;
+        }
+    }
+    fn sift_down(&mut self, mut pos: usize) {
+        loop {
+            let mut smallest = pos;
+            for child in [2 * pos + 1, 2 * pos + 2] {
+                if child < self.heap.len() && self.less(self.heap[child], self.heap[smallest]) {
+                    smallest = child;
+                }
+            }
+            if smallest == pos {
+                break;
+            }
+            self.swap(pos, smallest);
+            pos = smallest;
+        }
+    }
+}
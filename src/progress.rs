@@ -0,0 +1,185 @@
+use std::{
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// The state of a running search, gathered for each progress report.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// Steps taken by every [`crate::state::State`] so far.
+    pub nodes: u64,
+    /// Work items advanced so far.
+    pub processed: usize,
+    pub active: usize,
+    pub queued: usize,
+    /// The number of work items queued at each step.
+    pub queued_by_step: Vec<usize>,
+    /// The highest priority queued at each step, or `None` if nothing is.
+    pub priorities: Vec<Option<isize>>,
+    /// The number of work items at each step whose predecessors have all been
+    /// found.
+    pub completed: Vec<usize>,
+    pub best_step: usize,
+    pub target_step: usize,
+    /// The number of boards found at each step.
+    pub found: Vec<usize>,
+    pub index_bytes: usize,
+}
+
+/// Reports the progress of a search on stderr: as a status line redrawn in
+/// place when stderr is a terminal, and otherwise as one `key=value` record per
+/// report so that logs can be parsed.
+pub struct ProgressReporter {
+    tty: bool,
+    start: Instant,
+    last: (Instant, u64, usize),
+    /// The status line currently drawn, if any.
+    line: Option<String>,
+}
+
+impl Default for ProgressReporter {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            tty: std::io::stderr().is_terminal(),
+            start: now,
+            last: (now, 0, 0),
+            line: None,
+        }
+    }
+}
+
+fn join<T: ToString>(values: &[T], separator: &str) -> String {
+    let values: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+    values.join(separator)
+}
+
+impl ProgressReporter {
+    pub fn report(&mut self, progress: &Progress) {
+        let now = Instant::now();
+        let seconds = (now - self.last.0).as_secs_f64();
+        let node_rate = (progress.nodes - self.last.1) as f64 / seconds;
+        let item_rate = (progress.processed - self.last.2) as f64 / seconds;
+        self.last = (now, progress.nodes, progress.processed);
+        let elapsed = now - self.start;
+        // The time to work through the queued items at the current rate. New
+        // items keep arriving, so this is only a rough guide.
+        let eta =
+            (item_rate > 0.0).then(|| Duration::from_secs_f64(progress.queued as f64 / item_rate));
+        let memory = resident_memory();
+
+        if self.tty {
+            let line = format!(
+                "{} | {} nodes ({}/s) | {} items ({:.1}/s) | {} active, {} queued ({}) | done {} | best {}/{} | found {} | {} | eta {}",
+                format_duration(elapsed),
+                format_count(progress.nodes as f64),
+                format_count(node_rate),
+                progress.processed,
+                item_rate,
+                progress.active,
+                progress.queued,
+                join(&progress.queued_by_step, " "),
+                join(&progress.completed, " "),
+                progress.best_step,
+                progress.target_step,
+                join(&progress.found, " "),
+                match memory {
+                    Some(bytes) => format!(
+                        "{} MiB (index {} MiB)",
+                        bytes >> 20,
+                        progress.index_bytes >> 20
+                    ),
+                    None => format!("index {} MiB", progress.index_bytes >> 20),
+                },
+                eta.map_or("-".to_string(), format_duration),
+            );
+            self.line = Some(line);
+            self.redraw();
+        } else {
+            let priorities: Vec<String> = progress
+                .priorities
+                .iter()
+                .map(|priority| priority.map_or("-".to_string(), |p| p.to_string()))
+                .collect();
+            let mut stderr = std::io::stderr().lock();
+            writeln!(
+                stderr,
+                "progress elapsed={:.1} nodes={} nodes_per_sec={:.0} processed={} items_per_sec={:.2} active={} queued={} queued_by_step={} priorities={} completed={} best_step={} target_step={} found={} rss_bytes={} index_bytes={} eta_secs={}",
+                elapsed.as_secs_f64(),
+                progress.nodes,
+                node_rate,
+                progress.processed,
+                item_rate,
+                progress.active,
+                progress.queued,
+                join(&progress.queued_by_step, ","),
+                priorities.join(","),
+                join(&progress.completed, ","),
+                progress.best_step,
+                progress.target_step,
+                join(&progress.found, ","),
+                memory.map_or("-".to_string(), |bytes| bytes.to_string()),
+                progress.index_bytes,
+                eta.map_or("-".to_string(), |eta| format!("{:.0}", eta.as_secs_f64())),
+            )
+            .and_then(|()| stderr.flush())
+            .ok();
+        }
+    }
+    /// Draws the status line over whatever the cursor's line holds.
+    fn redraw(&self) {
+        if let Some(line) = &self.line {
+            let mut stderr = std::io::stderr().lock();
+            write!(stderr, "\r\x1b[K{}", line)
+                .and_then(|()| stderr.flush())
+                .ok();
+        }
+    }
+    /// Prints `text` to stdout. On a terminal, the status line is cleared
+    /// first and drawn again below the text, so the two do not run together.
+    pub fn print_above(&self, text: &str) {
+        if self.line.is_some() {
+            eprint!("\r\x1b[K");
+        }
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "{}", text)
+            .and_then(|()| stdout.flush())
+            .ok();
+        drop(stdout);
+        self.redraw();
+    }
+    /// Ends the status line so that later output starts on a line of its own.
+    pub fn finish(&mut self) {
+        if self.line.take().is_some() {
+            eprintln!();
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn format_count(count: f64) -> String {
+    match count {
+        c if c >= 1e9 => format!("{:.1}G", c / 1e9),
+        c if c >= 1e6 => format!("{:.1}M", c / 1e6),
+        c if c >= 1e3 => format!("{:.1}k", c / 1e3),
+        c => format!("{:.0}", c),
+    }
+}
+
+/// The resident memory of the process in bytes, where the platform reports
+/// it.
+fn resident_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
@@ -1,12 +1,12 @@
-use std::ops::Index;
+use std::sync::{Arc, RwLock};
 
-use metrohash::MetroHashMap;
+use metrohash::{MetroHashMap, MetroHashSet};
 use smallvec::{SmallVec, smallvec};
-use typenum::{Diff, Square, ToInt, U2};
+use typenum::{Diff, Prod, ToInt, U2};
 
 use crate::{
     bit_array::BitArray,
-    miniboard::{B, MacroboardSize, MiniboardSize},
+    miniboard::{B, Dimension, MacroDimension, MacroboardSize, MiniboardSize},
 };
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -15,21 +15,43 @@ pub enum Direction {
     Down,
     Left,
     Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
 }
 
 impl Direction {
-    pub const ALL: [Direction; 4] = [
+    pub const ALL: [Direction; 8] = [
         Direction::Up,
         Direction::Down,
         Direction::Left,
         Direction::Right,
+        Direction::UpLeft,
+        Direction::UpRight,
+        Direction::DownLeft,
+        Direction::DownRight,
     ];
-    pub fn shift<N: MiniboardSize>(&self, b: B<N>, n: usize) -> B<N> {
+    pub fn shift<W: MiniboardSize<H>, H: Dimension>(&self, b: B<W, H>, n: usize) -> B<W, H> {
+        self.shift_xy(b, n, n)
+    }
+    /// Shifts by `x` cells horizontally and `y` cells vertically, as far as
+    /// this direction moves along each axis.
+    pub fn shift_xy<W: MiniboardSize<H>, H: Dimension>(
+        &self,
+        b: B<W, H>,
+        x: usize,
+        y: usize,
+    ) -> B<W, H> {
         match self {
-            Direction::Up => b.shift_up(n),
-            Direction::Down => b.shift_down(n),
-            Direction::Left => b.shift_left(n),
-            Direction::Right => b.shift_right(n),
+            Direction::Up => b.shift_up(y),
+            Direction::Down => b.shift_down(y),
+            Direction::Left => b.shift_left(x),
+            Direction::Right => b.shift_right(x),
+            Direction::UpLeft => b.shift_up(y).shift_left(x),
+            Direction::UpRight => b.shift_up(y).shift_right(x),
+            Direction::DownLeft => b.shift_down(y).shift_left(x),
+            Direction::DownRight => b.shift_down(y).shift_right(x),
         }
     }
     #[inline(always)]
@@ -39,45 +61,49 @@ impl Direction {
             Self::Down => Self::Up,
             Self::Left => Self::Right,
             Self::Right => Self::Left,
+            Self::UpLeft => Self::DownRight,
+            Self::UpRight => Self::DownLeft,
+            Self::DownLeft => Self::UpRight,
+            Self::DownRight => Self::UpLeft,
         }
     }
     #[inline(always)]
     pub fn dx(self) -> i32 {
         match self {
-            Direction::Left => -1,
-            Direction::Right => 1,
+            Direction::Left | Direction::UpLeft | Direction::DownLeft => -1,
+            Direction::Right | Direction::UpRight | Direction::DownRight => 1,
             _ => 0,
         }
     }
     #[inline(always)]
     pub fn dy(self) -> i32 {
         match self {
-            Direction::Up => -1,
-            Direction::Down => 1,
+            Direction::Up | Direction::UpLeft | Direction::UpRight => -1,
+            Direction::Down | Direction::DownLeft | Direction::DownRight => 1,
             _ => 0,
         }
     }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
-pub enum Constraint<N: MacroboardSize> {
-    Neighbor { macroboard: B<N>, dir: Direction },
+pub enum Constraint<W: MacroboardSize<H>, H: MacroDimension> {
+    Neighbor { macroboard: B<W, H>, dir: Direction },
     Edge { dir: Direction },
 }
 
-impl<N: MacroboardSize> Constraint<N> {
-    pub fn neighbor(macroboard: B<N>, dir: Direction) -> Self {
+impl<W: MacroboardSize<H>, H: MacroDimension> Constraint<W, H> {
+    pub fn neighbor(macroboard: B<W, H>, dir: Direction) -> Self {
         Constraint::Neighbor {
             macroboard: dir.shift(macroboard, 1),
             dir,
         }
     }
-    pub fn compute(macroboard: B<N>) -> SmallVec<[Constraint<N>; 8]> {
+    pub fn compute(macroboard: B<W, H>) -> SmallVec<[Constraint<W, H>; 16]> {
         let mut result = SmallVec::new();
         result.extend(
             Direction::ALL
                 .into_iter()
-                .filter(|dir| dir.rev().shift(macroboard, N::INT - 2).step() == B::EMPTY)
+                .filter(|&dir| Self::is_edge(macroboard, dir))
                 .map(|dir| Constraint::Edge { dir }),
         );
         result.extend(Direction::ALL.into_iter().map(|dir| Constraint::Neighbor {
@@ -86,135 +112,750 @@ impl<N: MacroboardSize> Constraint<N> {
         }));
         result
     }
-    pub fn matches(self, b: B<N>) -> bool {
-        match self {
-            Constraint::Neighbor { macroboard, dir } => {
-                macroboard == dir.shift(dir.rev().shift(b, 1), 1)
-            }
-            Constraint::Edge { dir } => dir.rev().shift(b, N::INT - 2).step() == B::EMPTY,
-        }
+    /// Whether `b` can sit beyond the edge of the board in direction `dir`,
+    /// where it must leave the cells next to the board dead.
+    fn is_edge(b: B<W, H>, dir: Direction) -> bool {
+        dir.rev().shift_xy(b, W::INT - 2, H::INT - 2).step() == B::EMPTY
     }
 }
 
-#[derive(Debug, Default, Clone)]
-pub struct ReverseIndexSegment<N: MacroboardSize> {
-    map: MetroHashMap<Constraint<N>, Vec<B<N>>>,
-    all: Vec<B<N>>,
+/// The predecessors of one miniboard, with the positions in `all` satisfying
+/// each constraint. Edges are satisfied by a large share of `all`, so they are
+/// kept as bitsets. Neighbour constraints each pick out a few positions, which
+/// are stored next to each other in `members`. Every predecessor satisfies one
+/// neighbour constraint per direction, so `members` takes 32 bytes per
+/// predecessor, where a bitset per constraint would take `all.len() / 8` bytes
+/// per constraint: more as soon as a segment has over 256 constraints.
+/// `--index-stats` reports the predecessors and constraints per miniboard.
+#[derive(Debug, Clone)]
+pub struct ReverseIndexSegment<W: MacroboardSize<H>, H: MacroDimension> {
+    all: Vec<B<W, H>>,
+    /// One bitset over `all` per direction, indexed by `Direction as usize`.
+    edges: [Vec<u64>; 8],
+    members: Vec<u32>,
+    /// The range of `members` holding each neighbour constraint's positions,
+    /// which are sorted.
+    ranges: MetroHashMap<Constraint<W, H>, (u32, u32)>,
 }
 
-impl<N: MacroboardSize> ReverseIndexSegment<N> {
-    pub fn push(&mut self, b: B<N>) {
-        self.all.push(b);
-        for k in Constraint::compute(b) {
-            self.map.entry(k).or_default().push(b);
+impl<W: MacroboardSize<H>, H: MacroDimension> ReverseIndexSegment<W, H> {
+    /// Groups `all` by constraint after sorting it by live count.
+    pub fn new(mut all: Vec<B<W, H>>) -> Self {
+        all.sort_by_key(|b| b.live_count());
+        let mut edges: [Vec<u64>; 8] = Default::default();
+        for edge in &mut edges {
+            edge.resize(all.len().div_ceil(64), 0);
         }
-    }
-    pub fn sort(&mut self) {
-        self.all.sort_by_key(|b| b.live_count());
-        for v in self.map.values_mut() {
-            v.sort_by_key(|b| b.live_count());
+        let mut counts: MetroHashMap<Constraint<W, H>, u32> = MetroHashMap::default();
+        for (position, &b) in all.iter().enumerate() {
+            for k in Constraint::compute(b) {
+                match k {
+                    Constraint::Edge { dir } => {
+                        edges[dir as usize][position / 64] |= 1 << (position % 64);
+                    }
+                    Constraint::Neighbor { .. } => *counts.entry(k).or_default() += 1,
+                }
+            }
+        }
+        let mut start = 0;
+        let mut ranges: MetroHashMap<_, _> = counts
+            .into_iter()
+            .map(|(k, count)| {
+                start += count;
+                (k, (start - count, start - count))
+            })
+            .collect();
+        let mut members = vec![0; start as usize];
+        for (position, &b) in all.iter().enumerate() {
+            for k in Constraint::compute(b) {
+                if let Some((_, end)) = ranges.get_mut(&k) {
+                    members[*end as usize] = position as u32;
+                    *end += 1;
+                }
+            }
         }
+        ReverseIndexSegment {
+            all,
+            edges,
+            members,
+            ranges,
+        }
+    }
+    fn words(&self) -> usize {
+        self.all.len().div_ceil(64)
+    }
+    /// Word `i` of the bitset of positions satisfying every edge in the
+    /// `edges` mask, indexed by `Direction as usize`.
+    fn edge_word(&self, edges: u8, i: usize) -> u64 {
+        Direction::ALL
+            .into_iter()
+            .filter(|&dir| edges & (1 << dir as usize) != 0)
+            .fold(u64::MAX, |word, dir| word & self.edges[dir as usize][i])
+    }
+    /// Every predecessor, sorted by live count.
+    pub fn all(&self) -> &[B<W, H>] {
+        &self.all
+    }
+    /// Approximate heap usage in bytes.
+    pub fn memory(&self) -> usize {
+        self.all.capacity() * size_of::<B<W, H>>()
+            + self.edges.iter().map(|edge| edge.capacity()).sum::<usize>() * size_of::<u64>()
+            + self.members.capacity() * size_of::<u32>()
+            + self.ranges.capacity() * (size_of::<(Constraint<W, H>, (u32, u32))>() + 1)
     }
 }
 
-impl<N: MacroboardSize> Index<Constraint<N>> for ReverseIndexSegment<N> {
-    type Output = [B<N>];
+/// The size of the segments computed so far.
+#[derive(Debug, Clone, Copy)]
+pub struct IndexStats {
+    pub segments: usize,
+    pub macroboards: usize,
+    pub bytes: usize,
+}
 
-    fn index(&self, constraint: Constraint<N>) -> &Self::Output {
-        self.map
-            .get(&constraint)
-            .map(|v| v.as_slice())
-            .unwrap_or(&[])
+impl std::fmt::Display for IndexStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} segments, {} macroboards, {:.1} MiB",
+            self.segments,
+            self.macroboards,
+            self.bytes as f64 / (1 << 20) as f64
+        )
     }
 }
 
-#[derive(Debug)]
-pub struct ReverseIndex<N: MacroboardSize>(Vec<ReverseIndexSegment<N>>);
+/// A summary of every segment of an index.
+#[derive(Debug, Clone)]
+pub struct IndexReport {
+    pub miniboards: usize,
+    /// Miniboards without predecessors.
+    pub orphans: usize,
+    /// The number of miniboards by predecessor count: entry `i` counts those
+    /// with between `2^(i-1)` and `2^i - 1` predecessors, and entry 0 the
+    /// orphans.
+    pub histogram: Vec<usize>,
+    pub max: usize,
+    pub mean: f64,
+    /// Neighbour constraints with at least one predecessor, over all segments.
+    pub constraint_keys: usize,
+    pub stats: IndexStats,
+}
 
-impl<N: MacroboardSize> ReverseIndex<N> {
-    pub fn compute() -> Self {
-        let mut index = Vec::new();
-        let size = 1 << <Square<N>>::INT;
-        index.resize(size as usize, ReverseIndexSegment::default());
-        for i in 0..size {
-            let b: B<N> = B(BitArray::from_u64(i));
-            let b_small = b.step();
+impl std::fmt::Display for IndexReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Miniboards: {} ({} orphaned)",
+            self.miniboards, self.orphans
+        )?;
+        writeln!(
+            f,
+            "Predecessors per miniboard: mean {:.1}, max {}",
+            self.mean, self.max
+        )?;
+        for (i, &count) in self.histogram.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+            match i {
+                0 | 1 => writeln!(f, "    {}: {}", i, count)?,
+                _ => writeln!(f, "    {}-{}: {}", 1 << (i - 1), (1 << i) - 1, count)?,
+            }
+        }
+        writeln!(
+            f,
+            "Neighbour constraint keys: {} ({:.1} per miniboard)",
+            self.constraint_keys,
+            self.constraint_keys as f64 / self.miniboards as f64
+        )?;
+        write!(f, "Index: {}", self.stats)
+    }
+}
+
+/// Tiles with at most this many cells have every segment computed up front by
+/// enumerating all macroboards. Larger tiles compute segments as needed.
+const MAX_EAGER_CELLS: usize = 25;
+
+type Segments<W, H> = MetroHashMap<B<Diff<W, U2>, Diff<H, U2>>, Arc<ReverseIndexSegment<W, H>>>;
+
+/// The predecessors of each miniboard, grouped by the constraints they satisfy.
+/// Segments are shared between worker threads and never evicted.
+#[derive(Debug, Default)]
+pub struct ReverseIndex<W: MacroboardSize<H>, H: MacroDimension> {
+    segments: RwLock<Segments<W, H>>,
+}
 
-            index[b_small.0.to_u64() as usize].push(b);
+impl<W: MacroboardSize<H>, H: MacroDimension> ReverseIndex<W, H> {
+    pub fn new() -> Self {
+        if <Prod<W, H>>::INT <= MAX_EAGER_CELLS {
+            Self::compute()
+        } else {
+            Self::default()
         }
-        for item in &mut index {
-            item.sort();
+    }
+    /// Builds every segment by stepping each macroboard.
+    fn compute() -> Self {
+        let mut segments: MetroHashMap<_, Vec<B<W, H>>> = MetroHashMap::default();
+        for i in 0..1u64 << <Prod<W, H>>::INT {
+            let b: B<W, H> = B(BitArray::from_u64(i));
+            segments.entry(b.step()).or_default().push(b);
+        }
+        let segments = segments
+            .into_iter()
+            .map(|(miniboard, all)| (miniboard, Arc::new(ReverseIndexSegment::new(all))))
+            .collect();
+        ReverseIndex {
+            segments: RwLock::new(segments),
+        }
+    }
+    pub fn stats(&self) -> IndexStats {
+        let segments = self.segments.read().unwrap();
+        IndexStats {
+            segments: segments.len(),
+            macroboards: segments.values().map(|segment| segment.all.len()).sum(),
+            bytes: segments.values().map(|segment| segment.memory()).sum(),
         }
-        ReverseIndex(index)
+    }
+    /// Computes the segment of every miniboard and summarises them.
+    pub fn report(&self) -> IndexReport {
+        let cells = (W::INT - 2) * (H::INT - 2);
+        let mut report = IndexReport {
+            miniboards: 1 << cells,
+            orphans: 0,
+            histogram: Vec::new(),
+            max: 0,
+            mean: 0.0,
+            constraint_keys: 0,
+            stats: self.stats(),
+        };
+        let mut total = 0;
+        for i in 0..1u64 << cells {
+            let segment = self.segment(B(BitArray::from_u64(i)));
+            let count = segment.all.len();
+            let bucket = (usize::BITS - count.leading_zeros()) as usize;
+            if report.histogram.len() <= bucket {
+                report.histogram.resize(bucket + 1, 0);
+            }
+            report.histogram[bucket] += 1;
+            report.orphans += (count == 0) as usize;
+            report.max = report.max.max(count);
+            report.constraint_keys += segment.ranges.len();
+            total += count;
+        }
+        report.mean = total as f64 / report.miniboards as f64;
+        report.stats = self.stats();
+        report
+    }
+    /// The segment for `miniboard`, searching for its predecessors if no
+    /// worker has needed it before.
+    pub fn segment(
+        &self,
+        miniboard: B<Diff<W, U2>, Diff<H, U2>>,
+    ) -> Arc<ReverseIndexSegment<W, H>> {
+        if let Some(segment) = self.segments.read().unwrap().get(&miniboard) {
+            return segment.clone();
+        }
+        let segment = ReverseIndexSegment::new(Self::predecessors(miniboard));
+        self.segments
+            .write()
+            .unwrap()
+            .entry(miniboard)
+            .or_insert_with(|| Arc::new(segment))
+            .clone()
+    }
+    /// Finds every macroboard that steps to `miniboard`, assigning cells in
+    /// row-major order and checking each inner cell as soon as its
+    /// neighbourhood is complete.
+    fn predecessors(miniboard: B<Diff<W, U2>, Diff<H, U2>>) -> Vec<B<W, H>> {
+        fn search<W: MacroboardSize<H>, H: MacroDimension>(
+            b: &mut B<W, H>,
+            cell: usize,
+            miniboard: B<Diff<W, U2>, Diff<H, U2>>,
+            result: &mut Vec<B<W, H>>,
+        ) {
+            if cell == W::INT * H::INT {
+                debug_assert_eq!(b.step(), miniboard, "Predecessor search is wrong");
+                result.push(*b);
+                return;
+            }
+            let (x, y) = (cell % W::INT, cell / W::INT);
+            for value in [false, true] {
+                b.set(x, y, value);
+                if x >= 2 && y >= 2 {
+                    let mut count = 0;
+                    for dy in 0..3 {
+                        for dx in 0..3 {
+                            count += b.get(x - dx, y - dy) as usize;
+                        }
+                    }
+                    let alive = b.get(x - 1, y - 1);
+                    let next = count == 3 || (alive && count == 4);
+                    if next != miniboard.get(x - 2, y - 2) {
+                        continue;
+                    }
+                }
+                search(b, cell + 1, miniboard, result);
+            }
+            b.set(x, y, false);
+        }
+        let (mut b, mut result) = (B::EMPTY, Vec::new());
+        search(&mut b, 0, miniboard, &mut result);
+        result
     }
 }
 
-impl<N: MacroboardSize> Index<B<Diff<N, U2>>> for ReverseIndex<N> {
-    type Output = ReverseIndexSegment<N>;
+/// Neighbour constraints kept unevaluated in [`OptionSet::Neighbors`]. A key
+/// constrained by more neighbours than this is materialised as a bitset.
+const MAX_LAZY_NEIGHBORS: usize = 2;
 
-    fn index(&self, miniboard: B<Diff<N, U2>>) -> &Self::Output {
-        &self.0[miniboard.0.to_u64() as usize]
-    }
+/// The longest neighbour group walked to find a lazy key's options. Keys whose
+/// groups are all longer are materialised as a bitset, so that finding the
+/// `n`th option never walks more than this many positions.
+const MAX_LAZY_GROUP: usize = 64;
+
+/// A subset of the positions in a segment's `all` which can be read a word
+/// of 64 positions at a time.
+#[derive(Debug, Clone)]
+enum WordSet {
+    All,
+    One(u32),
+    /// The positions satisfying every edge in the `edges` mask, indexed by
+    /// `Direction as usize`, read straight from the segment's edge bitsets.
+    Edges {
+        edges: u8,
+        len: usize,
+    },
+    Bits {
+        words: SmallVec<[u64; 2]>,
+        /// The number of bits set.
+        len: usize,
+    },
 }
 
-#[derive(Debug)]
-pub enum ReverseIndexKey<N: MacroboardSize> {
-    Unconstrained {
-        miniboard: B<Diff<N, U2>>,
+/// A subset of the positions in a segment's `all`.
+#[derive(Debug, Clone)]
+enum OptionSet {
+    Words(WordSet),
+    /// The positions satisfying every edge in the `edges` mask and lying in
+    /// every range of the segment's `members` in `neighbors`. Nothing is
+    /// allocated: the positions are found by walking the shortest range,
+    /// which is at most `MAX_LAZY_GROUP` long.
+    Neighbors {
+        edges: u8,
+        neighbors: SmallVec<[(u32, u32); MAX_LAZY_NEIGHBORS]>,
+        len: usize,
     },
-    Constrained {
-        miniboard: B<Diff<N, U2>>,
-        constraint: Constraint<N>,
+}
+
+impl WordSet {
+    /// Word `i` of the set, as a bitset over `segment`'s `all`.
+    fn word<W: MacroboardSize<H>, H: MacroDimension>(
+        &self,
+        segment: &ReverseIndexSegment<W, H>,
+        i: usize,
+    ) -> u64 {
+        match self {
+            WordSet::All => {
+                let len = segment.all.len();
+                if len >= (i + 1) * 64 {
+                    u64::MAX
+                } else {
+                    u64::MAX >> ((i + 1) * 64 - len)
+                }
+            }
+            &WordSet::One(position) if position as usize / 64 == i => 1 << (position % 64),
+            WordSet::One(_) => 0,
+            &WordSet::Edges { edges, .. } => segment.edge_word(edges, i),
+            WordSet::Bits { words, .. } => words[i],
+        }
+    }
+}
+
+/// The options of a cell: the macroboards of a segment which are still
+/// consistent with the cell's surroundings.
+#[derive(Debug, Clone)]
+pub struct ReverseIndexKey<W: MacroboardSize<H>, H: MacroDimension> {
+    segment: Arc<ReverseIndexSegment<W, H>>,
+    options: OptionSet,
+}
+
+/// Iterates the positions of a key in increasing order, either a word of the
+/// bitset at a time or, for keys with lazy neighbour constraints, through the
+/// shortest neighbour group.
+enum Positions<'a, W: MacroboardSize<H>, H: MacroDimension> {
+    Words {
+        segment: &'a ReverseIndexSegment<W, H>,
+        set: &'a WordSet,
+        next: usize,
+        base: usize,
+        word: u64,
     },
-    List {
-        options: SmallVec<[B<N>; 1]>,
+    Group {
+        key: &'a ReverseIndexKey<W, H>,
+        group: std::slice::Iter<'a, u32>,
     },
 }
 
-impl<N: MacroboardSize> Default for ReverseIndexKey<N> {
-    fn default() -> Self {
-        ReverseIndexKey::List {
-            options: smallvec![],
+impl<W: MacroboardSize<H>, H: MacroDimension> Iterator for Positions<'_, W, H> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            Positions::Words {
+                segment,
+                set,
+                next,
+                base,
+                word,
+            } => loop {
+                if *word != 0 {
+                    let bit = word.trailing_zeros() as usize;
+                    *word &= *word - 1;
+                    return Some(*base + bit);
+                }
+                if *next >= segment.words() {
+                    return None;
+                }
+                (*base, *word) = (*next * 64, set.word(segment, *next));
+                *next += 1;
+            },
+            Positions::Group { key, group } => group
+                .map(|&position| position as usize)
+                .find(|&position| key.contains(position)),
         }
     }
 }
 
-impl<N: MacroboardSize> ReverseIndexKey<N> {
-    pub fn constrain(&self, constraint: Constraint<N>, index: &ReverseIndex<N>) -> Self {
-        let existing_options = match self {
-            ReverseIndexKey::Unconstrained { miniboard } => {
-                return ReverseIndexKey::Constrained {
-                    miniboard: *miniboard,
-                    constraint,
-                };
+impl<W: MacroboardSize<H>, H: MacroDimension> ReverseIndexKey<W, H> {
+    pub fn new(miniboard: B<Diff<W, U2>, Diff<H, U2>>, index: &ReverseIndex<W, H>) -> Self {
+        ReverseIndexKey {
+            segment: index.segment(miniboard),
+            options: OptionSet::Words(WordSet::All),
+        }
+    }
+    fn group(&self, (start, end): (u32, u32)) -> &[u32] {
+        &self.segment.members[start as usize..end as usize]
+    }
+    fn contains(&self, position: usize) -> bool {
+        match &self.options {
+            OptionSet::Words(set) => {
+                set.word(&self.segment, position / 64) & (1 << (position % 64)) != 0
             }
-            ReverseIndexKey::Constrained {
-                miniboard,
-                constraint: existing,
-            } => &index[*miniboard][*existing],
-            ReverseIndexKey::List { options } => options,
-        };
-        ReverseIndexKey::List {
-            options: existing_options
-                .iter()
+            OptionSet::Neighbors {
+                edges, neighbors, ..
+            } => {
+                self.segment.edge_word(*edges, position / 64) & (1 << (position % 64)) != 0
+                    && neighbors
+                        .iter()
+                        .all(|&range| self.group(range).binary_search(&(position as u32)).is_ok())
+            }
+        }
+    }
+    fn positions(&self) -> Positions<'_, W, H> {
+        match &self.options {
+            OptionSet::Words(set) => Positions::Words {
+                segment: &self.segment,
+                set,
+                next: 0,
+                base: 0,
+                word: 0,
+            },
+            OptionSet::Neighbors { neighbors, .. } => {
+                let &range = neighbors
+                    .iter()
+                    .min_by_key(|(start, end)| end - start)
+                    .expect("Lazy keys have a neighbour constraint");
+                Positions::Group {
+                    key: self,
+                    group: self.group(range).iter(),
+                }
+            }
+        }
+    }
+    /// Clears the bits of `words` which are not options of this key.
+    fn intersect(&self, words: &mut [u64]) {
+        if let OptionSet::Words(set) = &self.options {
+            for (i, word) in words.iter_mut().enumerate() {
+                *word &= set.word(&self.segment, i);
+            }
+            return;
+        }
+        for (i, word) in words.iter_mut().enumerate() {
+            let mut bits = *word;
+            while bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                if !self.contains(i * 64 + bit) {
+                    *word &= !(1 << bit);
+                }
+            }
+        }
+    }
+    fn with_options(&self, options: OptionSet) -> Self {
+        ReverseIndexKey {
+            segment: self.segment.clone(),
+            options,
+        }
+    }
+    fn with_words(&self, words: SmallVec<[u64; 2]>) -> Self {
+        let len = words.iter().map(|word| word.count_ones() as usize).sum();
+        self.with_options(OptionSet::Words(WordSet::Bits { words, len }))
+    }
+    /// A key with the lazy constraints `edges` and `neighbors`, counting its
+    /// options. If every neighbour group is longer than `MAX_LAZY_GROUP`, the
+    /// options are materialised instead.
+    fn with_constraints(
+        &self,
+        edges: u8,
+        neighbors: SmallVec<[(u32, u32); MAX_LAZY_NEIGHBORS]>,
+    ) -> Self {
+        if neighbors.is_empty() {
+            let len = (0..self.segment.words())
+                .map(|i| self.segment.edge_word(edges, i).count_ones() as usize)
+                .sum();
+            return self.with_options(OptionSet::Words(WordSet::Edges { edges, len }));
+        }
+        let lazy = neighbors
+            .iter()
+            .any(|(start, end)| (end - start) as usize <= MAX_LAZY_GROUP);
+        let mut key = self.with_options(OptionSet::Neighbors {
+            edges,
+            neighbors,
+            len: 0,
+        });
+        if !lazy {
+            let mut words = smallvec![0; self.segment.words()];
+            for position in key.positions() {
+                words[position / 64] |= 1 << (position % 64);
+            }
+            return self.with_words(words);
+        }
+        let count = key.positions().count();
+        if let OptionSet::Neighbors { len, .. } = &mut key.options {
+            *len = count;
+        }
+        key
+    }
+    pub fn constrain(&self, constraint: Constraint<W, H>) -> Self {
+        let range = |constraint| {
+            self.segment
+                .ranges
+                .get(&constraint)
                 .copied()
-                .filter(|b| constraint.matches(*b))
-                .collect(),
+                .unwrap_or((0, 0))
+        };
+        match (&self.options, constraint) {
+            (OptionSet::Words(WordSet::All), Constraint::Edge { dir }) => {
+                return self.with_constraints(1 << dir as usize, SmallVec::new());
+            }
+            (OptionSet::Words(WordSet::All), Constraint::Neighbor { .. }) => {
+                return self.with_constraints(0, smallvec![range(constraint)]);
+            }
+            (OptionSet::Words(WordSet::Edges { edges, .. }), Constraint::Edge { dir }) => {
+                return self.with_constraints(edges | (1 << dir as usize), SmallVec::new());
+            }
+            (OptionSet::Words(WordSet::Edges { edges, .. }), Constraint::Neighbor { .. }) => {
+                return self.with_constraints(*edges, smallvec![range(constraint)]);
+            }
+            (
+                OptionSet::Neighbors {
+                    edges, neighbors, ..
+                },
+                Constraint::Edge { dir },
+            ) => {
+                return self.with_constraints(edges | (1 << dir as usize), neighbors.clone());
+            }
+            (
+                OptionSet::Neighbors {
+                    edges, neighbors, ..
+                },
+                Constraint::Neighbor { .. },
+            ) if neighbors.len() < MAX_LAZY_NEIGHBORS => {
+                let mut neighbors = neighbors.clone();
+                neighbors.push(range(constraint));
+                return self.with_constraints(*edges, neighbors);
+            }
+            _ => {}
         }
+        let mut words: SmallVec<[u64; 2]> = match constraint {
+            Constraint::Edge { dir } => self.segment.edges[dir as usize].iter().copied().collect(),
+            Constraint::Neighbor { .. } => {
+                let mut words = smallvec![0; self.segment.words()];
+                for &position in self.group(range(constraint)) {
+                    words[position as usize / 64] |= 1 << (position % 64);
+                }
+                words
+            }
+        };
+        self.intersect(&mut words);
+        self.with_words(words)
     }
-    pub fn options<'a, 'b: 'a>(&'a self, index: &'b ReverseIndex<N>) -> &'a [B<N>] {
-        match self {
-            ReverseIndexKey::Unconstrained { miniboard } => &index[*miniboard].all,
-            ReverseIndexKey::Constrained {
-                miniboard,
-                constraint,
-            } => &index[*miniboard][*constraint],
-            ReverseIndexKey::List { options } => options,
+    /// Keeps only the options that agree with at least one option of `other`,
+    /// the key of the neighbouring cell in direction `dir`.
+    ///
+    /// Each option is checked against the overlaps of `other`'s options, so
+    /// the cost grows with the number of options of both keys rather than the
+    /// size of the segment. Propagation only calls this when both keys have at
+    /// most `PROPAGATION_LIMIT` options.
+    pub fn constrain_any(&self, other: &Self, dir: Direction) -> Self {
+        let supported: MetroHashSet<B<W, H>> = other.options().map(|b| dir.shift(b, 1)).collect();
+        let mut words: SmallVec<[u64; 2]> = smallvec![0; self.segment.words()];
+        for position in self.positions() {
+            let b = self.segment.all[position];
+            if supported.contains(&dir.shift(dir.rev().shift(b, 1), 1)) {
+                words[position / 64] |= 1 << (position % 64);
+            }
+        }
+        self.with_words(words)
+    }
+    pub fn len(&self) -> usize {
+        match &self.options {
+            OptionSet::Words(WordSet::All) => self.segment.all.len(),
+            OptionSet::Words(WordSet::One(_)) => 1,
+            OptionSet::Words(WordSet::Edges { len, .. } | WordSet::Bits { len, .. })
+            | OptionSet::Neighbors { len, .. } => *len,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    pub fn options(&self) -> impl Iterator<Item = B<W, H>> + '_ {
+        self.positions().map(|position| self.segment.all[position])
+    }
+    /// The only option, if there is exactly one.
+    pub fn single(&self) -> Option<B<W, H>> {
+        match self.options {
+            OptionSet::Words(WordSet::One(position)) => Some(self.segment.all[position as usize]),
+            _ if self.len() == 1 => self.options().next(),
+            _ => None,
+        }
+    }
+    /// The position in `all` of the `n`th option.
+    fn position(&self, mut n: usize) -> usize {
+        debug_assert!(n < self.len(), "Option {} out of range", n);
+        let set = match &self.options {
+            OptionSet::Words(set) => set,
+            OptionSet::Neighbors { .. } => return self.positions().nth(n).unwrap(),
+        };
+        for i in 0..self.segment.words() {
+            let mut word = set.word(&self.segment, i);
+            let count = word.count_ones() as usize;
+            if n < count {
+                for _ in 0..n {
+                    word &= word - 1;
+                }
+                return i * 64 + word.trailing_zeros() as usize;
+            }
+            n -= count;
+        }
+        unreachable!("Option {} out of range", n)
+    }
+    pub fn nth(&self, n: usize) -> B<W, H> {
+        self.segment.all[self.position(n)]
+    }
+    /// Keeps only the `n`th option.
+    pub fn select(&self, n: usize) -> Self {
+        self.with_options(OptionSet::Words(WordSet::One(self.position(n) as u32)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typenum::{U4, U5};
+
+    use super::*;
+    use crate::rng::Rng;
+
+    fn satisfies<W: MacroboardSize<H>, H: MacroDimension>(
+        b: B<W, H>,
+        constraint: Constraint<W, H>,
+    ) -> bool {
+        Constraint::compute(b).contains(&constraint)
+    }
+
+    /// Checks every way of reading the options of `key` against `expected`,
+    /// the options found by filtering `all` directly.
+    fn check<W: MacroboardSize<H>, H: MacroDimension>(
+        key: &ReverseIndexKey<W, H>,
+        expected: &[B<W, H>],
+    ) {
+        assert_eq!(key.len(), expected.len());
+        assert_eq!(key.options().collect::<Vec<_>>(), expected);
+        // Every option of small keys, and a sample of those of large ones.
+        let step = expected.len().div_ceil(64).max(1);
+        for (n, &b) in expected.iter().enumerate().step_by(step) {
+            assert_eq!(key.nth(n), b);
+            let selected = key.select(n);
+            assert_eq!(selected.single(), Some(b));
+            assert_eq!(selected.options().collect::<Vec<_>>(), [b]);
         }
     }
-    pub fn one(b: B<N>) -> Self {
-        ReverseIndexKey::List {
-            options: smallvec![b],
+
+    /// A random constraint satisfied by some of `options`, or by none of them
+    /// if there are none.
+    fn random_constraint<W: MacroboardSize<H>, H: MacroDimension>(
+        rng: &mut Rng,
+        options: &[B<W, H>],
+    ) -> Constraint<W, H> {
+        let dir = Direction::ALL[rng.below(8)];
+        if options.is_empty() || rng.below(3) == 0 {
+            return Constraint::Edge { dir };
         }
+        let b = options[rng.below(options.len())];
+        Constraint::neighbor(dir.rev().shift(b, 1), dir)
+    }
+
+    fn check_keys<W: MacroboardSize<H>, H: MacroDimension>(seed: u64) {
+        let index = ReverseIndex::<W, H>::new();
+        let mut rng = Rng::new(seed);
+        let cells = (W::INT - 2) * (H::INT - 2);
+        let segments: Vec<_> = (0..8)
+            .map(|_| index.segment(B(BitArray::from_u64(rng.next_u64() % (1 << cells)))))
+            .collect();
+        for round in 0..100 {
+            let segment = &segments[round % segments.len()];
+            let mut key = ReverseIndexKey {
+                segment: segment.clone(),
+                options: OptionSet::Words(WordSet::All),
+            };
+            let mut expected = segment.all().to_vec();
+            check(&key, &expected);
+            for _ in 0..rng.below(5) + 1 {
+                let constraint = random_constraint(&mut rng, &expected);
+                key = key.constrain(constraint);
+                expected.retain(|&b| satisfies(b, constraint));
+                check(&key, &expected);
+            }
+            let other_segment = &segments[rng.below(segments.len())];
+            let mut other = ReverseIndexKey {
+                segment: other_segment.clone(),
+                options: OptionSet::Words(WordSet::All),
+            };
+            let mut other_expected = other_segment.all().to_vec();
+            for _ in 0..rng.below(4) {
+                let constraint = random_constraint(&mut rng, &other_expected);
+                other = other.constrain(constraint);
+                other_expected.retain(|&b| satisfies(b, constraint));
+            }
+            let dir = Direction::ALL[rng.below(8)];
+            let key = key.constrain_any(&other, dir);
+            let supported: MetroHashSet<_> = other_expected
+                .iter()
+                .map(|&o| Constraint::neighbor(o, dir))
+                .collect();
+            expected.retain(|&b| Constraint::compute(b).iter().any(|k| supported.contains(k)));
+            check(&key, &expected);
+        }
+    }
+
+    #[test]
+    fn keys_match_filtered_options_4x4() {
+        check_keys::<U4, U4>(0);
+    }
+
+    #[test]
+    fn keys_match_filtered_options_4x5() {
+        check_keys::<U4, U5>(1);
     }
 }
@@ -0,0 +1,88 @@
+use std::{fmt::Write as _, io};
+
+use crate::board::Board;
+
+/// A resumable record of a search: the boards still waiting to be searched
+/// and every board found so far, each tagged with its step.
+///
+/// Search state inside a [`crate::state::State`] is not saved, so queued
+/// boards are searched again from scratch on resume.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub queued: Vec<(usize, Board)>,
+    pub seen: Vec<(usize, Board)>,
+}
+
+impl Snapshot {
+    pub fn from_boards(boards: Vec<Board>) -> Self {
+        Snapshot {
+            queued: boards.into_iter().map(|board| (0, board)).collect(),
+            seen: Vec::new(),
+        }
+    }
+    pub fn load(path: &str) -> Result<Self, io::Error> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+    /// Reads a snapshot in the format written by `save`. Malformed or
+    /// truncated snapshots give an `InvalidData` error.
+    pub fn parse(content: &str) -> Result<Self, io::Error> {
+        let mut result = Snapshot::default();
+        for item in content.split("\n\n").filter(|item| !item.trim().is_empty()) {
+            let (header, rows) = item.split_once('\n').unwrap_or((item, ""));
+            let (kind, step) = header
+                .split_once(' ')
+                .and_then(|(kind, step)| Some((kind, step.parse().ok()?)))
+                .ok_or_else(|| invalid_data(format!("Invalid snapshot header: {}", header)))?;
+            let list = match kind {
+                "queued" => &mut result.queued,
+                "seen" => &mut result.seen,
+                _ => return Err(invalid_data(format!("Unknown snapshot entry: {}", kind))),
+            };
+            let width = rows.lines().next().map_or(0, str::len);
+            if width == 0 || rows.lines().any(|row| row.len() != width) {
+                return Err(invalid_data(format!(
+                    "Missing or uneven board rows after snapshot header: {}",
+                    header
+                )));
+            }
+            list.push((step, Board::parse(rows)));
+        }
+        Ok(result)
+    }
+    pub fn save(&self, path: &str) -> Result<(), io::Error> {
+        let mut content = String::new();
+        for (kind, list) in [("queued", &self.queued), ("seen", &self.seen)] {
+            for (step, board) in list {
+                write!(content, "{} {}\n{:?}\n", kind, step, board).unwrap();
+            }
+        }
+        std::fs::write(path, content)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated_snapshots_are_invalid_data() {
+        let content = "queued 0\n.#.\n##.\n\nseen 1\n#..\n.##\n";
+        let snapshot = Snapshot::parse(content).unwrap();
+        assert_eq!(snapshot.queued, [(0, Board::parse(".#.\n##.\n"))]);
+        assert_eq!(snapshot.seen, [(1, Board::parse("#..\n.##\n"))]);
+        // Every truncation either drops whole rows or is rejected.
+        for end in 0..content.len() {
+            if let Err(error) = Snapshot::parse(&content[..end]) {
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            }
+        }
+        for truncated in ["queued 0\n", "queued 0", "seen 1\n#..\n.#"] {
+            let error = Snapshot::parse(truncated).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
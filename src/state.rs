@@ -6,10 +6,64 @@ use smallvec::SmallVec;
 
 use crate::{
     board::Board,
-    miniboard::{B, MacroboardSize},
+    miniboard::{B, MacroDimension, MacroboardSize},
+    nogoods::{Nogood, NogoodDb},
+    priority_index::PriorityIndex,
     reverse_index::{Constraint, Direction, ReverseIndex, ReverseIndexKey},
+    rng::Rng,
+    symmetry::Symmetry,
 };
 
+/// When a [`State`] abandons its current search tree and starts over, keeping
+/// the cell weights it has learned so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// Restart after `unit` times the next term of the Luby sequence
+    /// (1, 1, 2, 1, 1, 2, 4, ...) dead ends.
+    Luby { unit: usize },
+    /// Restart after `initial` dead ends, growing by `factor` each time.
+    Geometric { initial: usize, factor: f64 },
+}
+
+impl RestartPolicy {
+    /// Number of dead ends allowed before restart number `restarts`.
+    fn limit(self, restarts: u32) -> usize {
+        match self {
+            RestartPolicy::Never => usize::MAX,
+            RestartPolicy::Luby { unit } => unit.saturating_mul(luby(restarts as u64 + 1) as usize),
+            RestartPolicy::Geometric { initial, factor } => {
+                (initial as f64 * factor.powi(restarts as i32)).min(usize::MAX as f64) as usize
+            }
+        }
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// The `i`th term (1-based) of the Luby sequence.
+fn luby(mut i: u64) -> u64 {
+    loop {
+        let k = 64 - i.leading_zeros() as u64;
+        if i == (1 << k) - 1 {
+            return 1 << (k - 1);
+        }
+        i -= (1 << (k - 1)) - 1;
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SearchConfig {
+    /// Try the options of each cell in a random order rather than always
+    /// starting with the fewest live cells.
+    pub randomize: bool,
+    pub restarts: RestartPolicy,
+    pub seed: u64,
+}
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 enum InstructionPointer {
     #[default]
@@ -20,243 +74,570 @@ enum InstructionPointer {
     Return,
 }
 
+/// The cells whose current assignments explain why a frame's options failed.
 #[derive(Default, Debug)]
-struct StackFrame<N: MacroboardSize> {
+struct ConflictSet {
+    /// Some failure could not be explained (or a solution was found), so the
+    /// frame must be backtracked into chronologically.
+    unexplained: bool,
+    cells: SmallVec<[usize; 8]>,
+}
+
+impl ConflictSet {
+    fn unexplained() -> Self {
+        ConflictSet {
+            unexplained: true,
+            cells: SmallVec::new(),
+        }
+    }
+    fn add(&mut self, cells: impl IntoIterator<Item = usize>, except: usize) {
+        for cell in cells {
+            if cell != except && !self.cells.contains(&cell) {
+                self.cells.push(cell);
+            }
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+struct StackFrame {
     ip: InstructionPointer,
     idx: usize,
     priority: usize,
     opt_index: usize,
-    saved_options: SmallVec<[ReverseIndexKey<N>; 4]>,
-    original_options: ReverseIndexKey<N>,
+    /// Option `opt_index` is option number `opt_offset + opt_index *
+    /// opt_stride` modulo the number of options. The stride is coprime with
+    /// that number, so every option is tried once.
+    opt_offset: usize,
+    opt_stride: usize,
+    trail_len: usize,
+    conflicts: ConflictSet,
+    /// A deeper frame failed for reasons unrelated to this frame's choice,
+    /// so its remaining options can be skipped.
+    backjump: bool,
 }
 #[derive(Debug)]
-pub struct State<N: MacroboardSize> {
-    board: Vec<CellState<N>>,
+pub struct State<W: MacroboardSize<H>, H: MacroDimension> {
+    board: Vec<CellState<W, H>>,
+    /// Orders the cells by `CellState::priority`, kept in sync by
+    /// `set_priority`.
+    queue: PriorityIndex,
     stride: usize,
-    stack: Vec<StackFrame<N>>,
-    frame: StackFrame<N>,
+    stack: Vec<StackFrame>,
+    frame: StackFrame,
+    /// Previous keys of every cell changed since the search started, so that
+    /// each frame can undo the changes made after `StackFrame::trail_len`.
+    trail: Vec<(usize, ReverseIndexKey<W, H>)>,
+    config: SearchConfig,
+    rng: Rng,
+    initial_keys: Vec<ReverseIndexKey<W, H>>,
+    dead_ends: usize,
+    restarts: u32,
+    nogoods: NogoodDb<W, H>,
+    /// The conflict set of the most recently finished frame, to be absorbed
+    /// by its parent.
+    returned: ConflictSet,
+    /// Whether failures are explained, which is what enables nogoods and
+    /// backjumping. Only turned off to check that learning loses nothing.
+    learning: bool,
+    /// Symmetries every solution must have. Committing an option gives each
+    /// mirror image of the cell the mirrored option.
+    symmetries: SmallVec<[Symmetry; 8]>,
+    /// Steps taken by `advance` so far.
+    nodes: u64,
+    /// Steps taken plus keys constrained by propagation, which is what the
+    /// steps given to `advance` are counted against.
+    work: u64,
 }
 
 #[derive(Debug)]
-struct CellState<N: MacroboardSize> {
-    key: ReverseIndexKey<N>,
+struct CellState<W: MacroboardSize<H>, H: MacroDimension> {
+    key: ReverseIndexKey<W, H>,
     priority: usize,
     weight: usize,
 }
 
 const INITIAL_WEIGHT: usize = 1000;
 const WEIGHT_ADJUST: usize = 10;
+/// Propagation only checks the options of an uncommitted cell against those of
+/// a neighbour when both have at most this many: with more, nearly every option
+/// still has support, so the check costs far more than it saves.
+const PROPAGATION_LIMIT: usize = 64;
 
-impl<N: MacroboardSize> CellState<N> {
-    pub fn recompute_priority(&mut self, index: &ReverseIndex<N>) {
-        if self.priority != usize::MAX {
-            self.priority = self.key.options(index).len() + self.weight;
-        }
-    }
-}
-
-impl<N: MacroboardSize> State<N> {
-    fn iter_rows(&self) -> impl Iterator<Item = &[CellState<N>]> {
+impl<W: MacroboardSize<H>, H: MacroDimension> State<W, H> {
+    fn iter_rows(&self) -> impl Iterator<Item = &[CellState<W, H>]> {
         self.board.chunks(self.stride)
     }
-    fn generate_solution_row(
-        &self,
-        index: &ReverseIndex<N>,
-        row: &[CellState<N>],
-        y2: usize,
-        output: &mut BitVec,
-    ) {
+    fn generate_solution_row(&self, row: &[CellState<W, H>], y2: usize, output: &mut BitVec) {
         for (x, cell) in row.iter().enumerate() {
-            let opts = cell.key.options(index);
-            debug_assert_eq!(opts.len(), 1, "Expected exactly one option",);
-            let opt = opts[0];
+            let opt = cell.key.single().expect("Expected exactly one option");
             if x == 0 {
-                for x2 in 0..(N::INT - 1) {
+                for x2 in 0..(W::INT - 1) {
                     output.push(opt.get(x2, y2));
                 }
             }
-            output.push(opt.get(N::INT - 1, y2));
+            output.push(opt.get(W::INT - 1, y2));
         }
     }
-    fn generate_solution(&self, index: &ReverseIndex<N>) -> Board {
+    fn generate_solution(&self) -> Board {
         let mut solution = BitVec::new();
         for (y, row) in self.iter_rows().enumerate() {
             if y == 0 {
-                for y2 in 0..(N::INT - 1) {
-                    self.generate_solution_row(index, row, y2, &mut solution);
+                for y2 in 0..(H::INT - 1) {
+                    self.generate_solution_row(row, y2, &mut solution);
                 }
             }
-            self.generate_solution_row(index, row, N::INT - 1, &mut solution);
+            self.generate_solution_row(row, H::INT - 1, &mut solution);
         }
-        let mut result = Board::new(solution, self.stride + N::INT - 1);
-        result.trim();
-        result
+        Board::new(solution, self.stride + W::INT - 1)
     }
-    pub fn new(board: &Board, index: &ReverseIndex<N>) -> Self {
+    pub fn new(board: &Board, index: &ReverseIndex<W, H>, config: SearchConfig, seed: u64) -> Self {
         let mut new_board = Vec::new();
-        for y in 0..board.height() + 3 - N::INT {
-            for x in 0..board.width() + 3 - N::INT {
+        for y in 0..board.height() + 3 - H::INT {
+            for x in 0..board.width() + 3 - W::INT {
                 let mut miniboard = B::EMPTY;
-                for dy in 0..(N::INT - 2) {
-                    for dx in 0..(N::INT - 2) {
+                for dy in 0..(H::INT - 2) {
+                    for dx in 0..(W::INT - 2) {
                         if board.get(x + dx, y + dy) {
                             miniboard.set(dx, dy, true);
                         }
                     }
                 }
-                let key = ReverseIndexKey::Unconstrained { miniboard };
+                let key = ReverseIndexKey::new(miniboard, index);
                 new_board.push(CellState {
-                    priority: key.options(index).len() + INITIAL_WEIGHT,
+                    priority: key.len() + INITIAL_WEIGHT,
                     key,
                     weight: INITIAL_WEIGHT,
                 });
             }
         }
-        let stride = board.width() + 3 - N::INT;
+        let stride = board.width() + 3 - W::INT;
         let mut result = Self {
+            queue: PriorityIndex::new(new_board.iter().map(|cell| cell.priority).collect()),
             board: new_board,
             stride,
             stack: Vec::with_capacity(stride * stride),
             frame: StackFrame::default(),
+            trail: Vec::new(),
+            config,
+            rng: Rng::new(seed),
+            initial_keys: Vec::new(),
+            dead_ends: 0,
+            restarts: 0,
+            nogoods: NogoodDb::default(),
+            returned: ConflictSet::default(),
+            learning: true,
+            symmetries: SmallVec::new(),
+            nodes: 0,
+            work: 0,
         };
-        result.clear_borders(index);
+        result.clear_borders();
+        result.initial_keys = result.board.iter().map(|cell| cell.key.clone()).collect();
         result
     }
-    pub fn clear_borders(&mut self, index: &ReverseIndex<N>) {
+    /// Restricts the search to solutions with each of `symmetries`, about
+    /// the centre of the board. Symmetries which swap the axes are skipped
+    /// unless both the board and the tiles are square, so solutions may still
+    /// need to be checked.
+    pub fn require_symmetries(&mut self, symmetries: &[Symmetry]) {
+        let square = W::INT == H::INT && self.stride * self.stride == self.board.len();
+        self.symmetries = symmetries
+            .iter()
+            .copied()
+            .filter(|symmetry| square || !symmetry.swaps_axes())
+            .collect();
+    }
+    fn restart(&mut self) {
+        for idx in 0..self.board.len() {
+            let cell = &mut self.board[idx];
+            cell.key = self.initial_keys[idx].clone();
+            let priority = cell.key.len() + cell.weight;
+            self.set_priority(idx, priority);
+        }
+        self.stack.clear();
+        self.frame = StackFrame::default();
+        self.trail.clear();
+        self.returned = ConflictSet::default();
+        self.dead_ends = 0;
+        self.restarts += 1;
+    }
+    fn set_priority(&mut self, idx: usize, priority: usize) {
+        self.board[idx].priority = priority;
+        self.queue.set(idx, priority);
+    }
+    fn recompute_priority(&mut self, idx: usize) {
+        let cell = &self.board[idx];
+        if cell.priority != usize::MAX {
+            self.set_priority(idx, cell.key.len() + cell.weight);
+        }
+    }
+    fn committed(&self, idx: usize) -> Option<B<W, H>> {
+        if self.board[idx].priority != usize::MAX {
+            return None;
+        }
+        self.board[idx].key.single()
+    }
+    fn neighbors(&self, idx: usize) -> impl Iterator<Item = (usize, Direction)> + use<W, H> {
+        let w = self.stride;
+        let h = self.board.len() / w;
+        Direction::ALL.into_iter().filter_map(move |dir| {
+            let nx = (idx % w).wrapping_add(dir.dx() as usize);
+            let ny = (idx / w).wrapping_add(dir.dy() as usize);
+            (nx < w && ny < h).then(|| (ny * w + nx, dir))
+        })
+    }
+    /// The cell which `symmetry` maps cell `idx` to.
+    fn mirror(&self, idx: usize, symmetry: Symmetry) -> usize {
+        let w = self.stride;
+        let h = self.board.len() / w;
+        let (x, y) = symmetry.apply(idx % w, idx / w, w, h);
+        y * w + x
+    }
+    /// Gives each mirror image of cell `idx` the mirrored image of `b`, the
+    /// option just selected for it, recording the changes on the trail.
+    /// Returns the cells changed, or `None` if a mirror image cannot take its
+    /// option.
+    fn tie_mirrors(&mut self, idx: usize, b: B<W, H>) -> Option<SmallVec<[usize; 8]>> {
+        let mut tied = SmallVec::new();
+        for i in 0..self.symmetries.len() {
+            let symmetry = self.symmetries[i];
+            let (m, t) = (self.mirror(idx, symmetry), b.transform(symmetry));
+            if m == idx {
+                if t != b {
+                    return None;
+                }
+                continue;
+            }
+            let key = &self.board[m].key;
+            if self.board[m].priority == usize::MAX {
+                if key.single() != Some(t) {
+                    return None;
+                }
+                continue;
+            }
+            let n = key.options().position(|option| option == t)?;
+            if key.len() > 1 {
+                let selected = key.select(n);
+                let prev = mem::replace(&mut self.board[m].key, selected);
+                self.recompute_priority(m);
+                self.trail.push((m, prev));
+                tied.push(m);
+            }
+        }
+        Some(tied)
+    }
+    /// Explains why cell `idx` has no options by finding a minimal set of
+    /// committed neighbours which on their own leave it with no options.
+    /// Returns `None` if the committed neighbours are not enough, meaning the
+    /// options were removed by propagation through uncommitted cells.
+    fn explain(&self, idx: usize) -> Option<Nogood<W, H>> {
+        if !self.learning {
+            return None;
+        }
+        let mut literals: SmallVec<[_; 8]> = self
+            .neighbors(idx)
+            .filter_map(|(n, dir)| Some((n, self.committed(n)?, dir)))
+            .collect();
+        let eliminates = |literals: &[(usize, B<W, H>, Direction)]| {
+            let mut key = self.initial_keys[idx].clone();
+            for &(_, b, dir) in literals {
+                key = key.constrain(Constraint::neighbor(b, dir));
+            }
+            key.is_empty()
+        };
+        if !eliminates(&literals) {
+            return None;
+        }
+        let mut i = 0;
+        while i < literals.len() {
+            let removed = literals.remove(i);
+            if !eliminates(&literals) {
+                literals.insert(i, removed);
+                i += 1;
+            }
+        }
+        Some(literals.into_iter().map(|(n, b, _)| (n, b)).collect())
+    }
+    /// Explains the options of cell `idx` already removed by propagation,
+    /// which a frame for the cell never tries. They are explained by the
+    /// committed neighbours if those alone remove the same options.
+    fn pruned_by(&self, idx: usize) -> ConflictSet {
+        let len = self.board[idx].key.len();
+        if len == self.initial_keys[idx].len() {
+            return ConflictSet::default();
+        }
+        let mut key = self.initial_keys[idx].clone();
+        let mut cells = SmallVec::new();
+        for (n, dir) in self.neighbors(idx) {
+            if let Some(b) = self.committed(n) {
+                key = key.constrain(Constraint::neighbor(b, dir));
+                cells.push(n);
+            }
+        }
+        if key.len() == len {
+            ConflictSet {
+                unexplained: false,
+                cells,
+            }
+        } else {
+            ConflictSet::unexplained()
+        }
+    }
+    /// Records that the current frame's option failed because of `cells`.
+    fn fail_option(&mut self, cells: impl IntoIterator<Item = usize>) {
+        self.dead_ends += 1;
+        let idx = self.frame.idx;
+        self.frame.conflicts.add(cells, idx);
+    }
+    /// Merges the conflict set of a finished child frame into the current
+    /// frame. If the child failed regardless of this frame's choice, the rest
+    /// of this frame's options are skipped.
+    fn absorb(&mut self, returned: ConflictSet) {
+        if returned.unexplained {
+            self.frame.conflicts.unexplained = true;
+        } else if returned.cells.contains(&self.frame.idx) {
+            let idx = self.frame.idx;
+            self.frame.conflicts.add(returned.cells, idx);
+        } else {
+            self.frame.conflicts = returned;
+            self.frame.backjump = true;
+        }
+    }
+    /// The number of the current frame's option among its cell's options.
+    fn option_number(&self) -> usize {
+        let len = self.board[self.frame.idx].key.len();
+        (self.frame.opt_offset + self.frame.opt_index * self.frame.opt_stride) % len
+    }
+    fn option(&self) -> B<W, H> {
+        self.board[self.frame.idx].key.nth(self.option_number())
+    }
+    pub fn clear_borders(&mut self) {
         let w = self.stride;
         let h = self.board.len() / w;
         for y in 0..h {
-            self.board[y * w].key = self.board[y * w].key.constrain(
-                Constraint::Edge {
-                    dir: Direction::Left,
-                },
-                index,
-            );
-            self.board[y * w].recompute_priority(index);
-            self.board[y * w + w - 1].key = self.board[y * w + w - 1].key.constrain(
-                Constraint::Edge {
+            self.board[y * w].key = self.board[y * w].key.constrain(Constraint::Edge {
+                dir: Direction::Left,
+            });
+            self.recompute_priority(y * w);
+            self.board[y * w + w - 1].key =
+                self.board[y * w + w - 1].key.constrain(Constraint::Edge {
                     dir: Direction::Right,
-                },
-                index,
-            );
-            self.board[y * w + w - 1].recompute_priority(index);
+                });
+            self.recompute_priority(y * w + w - 1);
         }
         for x in 0..w {
             self.board[x].key = self.board[x]
                 .key
-                .constrain(Constraint::Edge { dir: Direction::Up }, index);
-            self.board[x].recompute_priority(index);
-            self.board[(h - 1) * w + x].key = self.board[(h - 1) * w + x].key.constrain(
-                Constraint::Edge {
+                .constrain(Constraint::Edge { dir: Direction::Up });
+            self.recompute_priority(x);
+            self.board[(h - 1) * w + x].key =
+                self.board[(h - 1) * w + x].key.constrain(Constraint::Edge {
                     dir: Direction::Down,
-                },
-                index,
-            );
-            self.board[(h - 1) * w + x].recompute_priority(index);
+                });
+            self.recompute_priority((h - 1) * w + x);
+        }
+        for (idx, dir) in [
+            (0, Direction::UpLeft),
+            (w - 1, Direction::UpRight),
+            ((h - 1) * w, Direction::DownLeft),
+            (h * w - 1, Direction::DownRight),
+        ] {
+            self.board[idx].key = self.board[idx].key.constrain(Constraint::Edge { dir });
+            self.recompute_priority(idx);
+        }
+    }
+
+    /// Removes options from uncommitted cells that are no longer consistent
+    /// with any option of a neighbouring cell, starting from the neighbours of
+    /// `start` and continuing until nothing changes. Every change is recorded
+    /// on the trail. Returns the first cell left with no options, if any.
+    fn propagate(&mut self, start: usize) -> Result<(), usize> {
+        let mut pending = vec![start];
+        while let Some(idx) = pending.pop() {
+            let options = &self.board[idx].key;
+            if options.len() > PROPAGATION_LIMIT {
+                continue;
+            }
+            let mut changes: SmallVec<[(usize, ReverseIndexKey<W, H>); 8]> = SmallVec::new();
+            for (n, dir) in self.neighbors(idx) {
+                if self.board[n].priority == usize::MAX {
+                    continue;
+                }
+                let key = &self.board[n].key;
+                let new_key = if let Some(opt) = options.single() {
+                    key.constrain(Constraint::neighbor(opt, dir.rev()))
+                } else if key.len() <= PROPAGATION_LIMIT {
+                    key.constrain_any(options, dir.rev())
+                } else {
+                    continue;
+                };
+                self.work += 1;
+                if new_key.len() != key.len() {
+                    changes.push((n, new_key));
+                }
+            }
+            for (n, new_key) in changes {
+                let is_empty = new_key.is_empty();
+                let prev = mem::replace(&mut self.board[n].key, new_key);
+                self.recompute_priority(n);
+                self.trail.push((n, prev));
+                if is_empty {
+                    return Err(n);
+                }
+                pending.push(n);
+            }
         }
+        Ok(())
     }
 
     pub fn is_done(&self) -> bool {
         self.frame.ip == InstructionPointer::Return && self.stack.is_empty()
     }
 
-    pub fn advance(
-        &mut self,
-        index: &ReverseIndex<N>,
-        result: &mut MetroHashSet<Board>,
-        steps: usize,
-    ) -> bool {
-        let w = self.stride;
-        let h = self.board.len() / w;
+    /// Runs the search for up to `steps` steps, adding any solutions found to
+    /// `result`. Each key constrained by propagation counts as a step too, so
+    /// that the time taken follows `steps` however large the board is.
+    /// Solutions are not trimmed: they cover the target board plus a one cell
+    /// border on every side.
+    pub fn advance(&mut self, result: &mut MetroHashSet<Board>, steps: usize) -> bool {
         let mut success = false;
 
-        for _ in 0..steps {
+        let end = self.work + steps as u64;
+        while self.work < end {
+            self.nodes += 1;
+            self.work += 1;
+            if self.dead_ends >= self.config.restarts.limit(self.restarts) && !self.is_done() {
+                self.restart();
+            }
             match self.frame.ip {
                 InstructionPointer::Call => {
-                    (self.frame.idx, self.frame.priority) = self
-                        .board
-                        .iter()
-                        .enumerate()
-                        .map(move |(idx, cell)| (idx, cell.priority))
-                        .min_by_key(|&(_, priority)| priority)
-                        .unwrap();
+                    (self.frame.idx, self.frame.priority) = self.queue.min().unwrap();
 
                     if self.frame.priority == usize::MAX {
                         // Found solution
-                        result.insert(self.generate_solution(index));
+                        result.insert(self.generate_solution());
                         success = true;
+                        self.returned = ConflictSet::unexplained();
                         self.frame.ip = InstructionPointer::Return;
                         continue;
-                    } else if self.board[self.frame.idx].key.options(index).is_empty() {
+                    } else if self.board[self.frame.idx].key.is_empty() {
                         self.board[self.frame.idx].weight = self.board[self.frame.idx]
                             .weight
                             .saturating_sub(WEIGHT_ADJUST);
-                        self.board[self.frame.idx].recompute_priority(index);
+                        self.recompute_priority(self.frame.idx);
                         // No solution possible
+                        self.dead_ends += 1;
+                        self.returned = match self.explain(self.frame.idx) {
+                            Some(nogood) => ConflictSet {
+                                unexplained: false,
+                                cells: nogood.iter().map(|&(n, _)| n).collect(),
+                            },
+                            None => ConflictSet::unexplained(),
+                        };
                         self.frame.ip = InstructionPointer::Return;
                         continue;
                     }
 
-                    self.board[self.frame.idx].priority = usize::MAX;
+                    self.set_priority(self.frame.idx, usize::MAX);
+                    self.frame.conflicts = self.pruned_by(self.frame.idx);
+                    (self.frame.opt_offset, self.frame.opt_stride) = if self.config.randomize {
+                        let len = self.board[self.frame.idx].key.len();
+                        let stride = loop {
+                            let stride = self.rng.below(len);
+                            if gcd(stride, len) == 1 {
+                                break stride;
+                            }
+                        };
+                        (self.rng.below(len), stride)
+                    } else {
+                        (0, 1)
+                    };
 
                     self.frame.ip = InstructionPointer::LoopStart;
                 }
                 InstructionPointer::LoopStart => {
-                    let opt = self.board[self.frame.idx].key.options(index)[self.frame.opt_index];
-                    self.frame.original_options = mem::replace(
-                        &mut self.board[self.frame.idx].key,
-                        ReverseIndexKey::one(opt),
-                    );
-
-                    let mut conflicting = false;
-                    for dir in Direction::ALL {
-                        let nx = (self.frame.idx % w).wrapping_add(dir.dx() as usize);
-                        let ny = (self.frame.idx / w).wrapping_add(dir.dy() as usize);
-                        if nx < w && ny < h {
-                            let new_opts = self.board[ny * w + nx]
-                                .key
-                                .constrain(Constraint::neighbor(opt, dir.rev()), index);
-                            if new_opts.options(index).is_empty() {
-                                conflicting = true;
-                            }
-                            let prev_opts =
-                                mem::replace(&mut self.board[ny * w + nx].key, new_opts);
-                            self.board[ny * w + nx].recompute_priority(index);
-                            self.frame.saved_options.push(prev_opts);
-                        }
-                    }
+                    let opt = self.option();
+                    self.frame.trail_len = self.trail.len();
                     self.frame.ip = InstructionPointer::LoopMiddle;
-                    if !conflicting {
-                        self.stack.push(mem::take(&mut self.frame));
+                    let violated = self
+                        .nogoods
+                        .violated(self.frame.idx, opt, |n, b| self.committed(n) == Some(b));
+                    if let Some(nogood) = violated {
+                        let cells: SmallVec<[usize; 8]> = nogood.iter().map(|&(n, _)| n).collect();
+                        self.fail_option(cells);
+                        continue;
+                    }
+
+                    let selected = self.board[self.frame.idx].key.select(self.option_number());
+                    let prev = mem::replace(&mut self.board[self.frame.idx].key, selected);
+                    self.trail.push((self.frame.idx, prev));
+
+                    let Some(tied) = self.tie_mirrors(self.frame.idx, opt) else {
+                        // Only the choices of mirror images conflict, which no
+                        // nogood describes.
+                        self.fail_option([]);
+                        self.frame.conflicts.unexplained = true;
+                        continue;
+                    };
+                    let propagated = std::iter::once(self.frame.idx)
+                        .chain(tied)
+                        .try_for_each(|start| self.propagate(start));
+                    match propagated {
+                        Ok(()) => self.stack.push(mem::take(&mut self.frame)),
+                        Err(wiped) => match self.explain(wiped) {
+                            Some(nogood) => {
+                                let cells: SmallVec<[usize; 8]> =
+                                    nogood.iter().map(|&(n, _)| n).collect();
+                                self.nogoods.add(nogood);
+                                self.fail_option(cells);
+                            }
+                            None => {
+                                self.fail_option([]);
+                                self.frame.conflicts.unexplained = true;
+                            }
+                        },
                     }
                 }
                 InstructionPointer::LoopMiddle => {
-                    self.frame.saved_options.reverse();
-                    for dir in Direction::ALL {
-                        let nx = (self.frame.idx % w).wrapping_add(dir.dx() as usize);
-                        let ny = (self.frame.idx / w).wrapping_add(dir.dy() as usize);
-                        if nx < w && ny < h {
-                            self.board[ny * w + nx].key = self.frame.saved_options.pop().unwrap();
-                            self.board[ny * w + nx].recompute_priority(index);
-                        }
+                    while self.trail.len() > self.frame.trail_len {
+                        let (idx, key) = self.trail.pop().unwrap();
+                        self.board[idx].key = key;
+                        self.recompute_priority(idx);
                     }
 
-                    self.board[self.frame.idx].key = mem::take(&mut self.frame.original_options);
-
                     self.frame.opt_index += 1;
-                    if self.frame.opt_index < self.board[self.frame.idx].key.options(index).len() {
+                    if !self.frame.backjump
+                        && self.frame.opt_index < self.board[self.frame.idx].key.len()
+                    {
                         self.frame.ip = InstructionPointer::LoopStart;
                     } else {
                         self.frame.ip = InstructionPointer::LoopEnd;
                     }
                 }
                 InstructionPointer::LoopEnd => {
-                    self.board[self.frame.idx].priority = self.frame.priority;
+                    self.set_priority(self.frame.idx, self.frame.priority);
+                    let conflicts = mem::take(&mut self.frame.conflicts);
+                    if !conflicts.unexplained {
+                        // Every option failed because of the cells above, so
+                        // their current assignments can never hold together.
+                        let nogood = conflicts
+                            .cells
+                            .iter()
+                            .map(|&n| {
+                                let b = self.committed(n);
+                                (n, b.expect("Conflict cells must be committed"))
+                            })
+                            .collect();
+                        self.nogoods.add(nogood);
+                    }
+                    self.returned = conflicts;
                     self.frame.ip = InstructionPointer::Return;
                 }
                 InstructionPointer::Return => {
                     if let Some(f) = self.stack.pop() {
                         self.frame = f;
+                        let returned = mem::take(&mut self.returned);
+                        self.absorb(returned);
                     } else {
                         break;
                     }
@@ -267,12 +648,117 @@ impl<N: MacroboardSize> State<N> {
         success
     }
 
-    pub fn score(&self, index: &ReverseIndex<N>) -> usize {
+    pub fn nodes(&self) -> u64 {
+        self.nodes
+    }
+
+    pub fn score(&self) -> usize {
         (self
             .board
             .iter()
-            .map(|cell| cell.key.options(index).len() as u64)
+            .map(|cell| cell.key.len() as u64)
             .sum::<u64>()
             / self.board.len() as u64) as usize
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use typenum::U4;
+
+    use super::*;
+
+    fn predecessors(
+        target: &Board,
+        index: &ReverseIndex<U4, U4>,
+        setup: impl FnOnce(&mut State<U4, U4>),
+    ) -> MetroHashSet<Board> {
+        let mut state = State::new(target, index, SearchConfig::default(), 0);
+        setup(&mut state);
+        let mut result = MetroHashSet::default();
+        while !state.is_done() {
+            state.advance(&mut result, 10000);
+        }
+        result
+    }
+
+    #[test]
+    fn learning_keeps_every_predecessor() {
+        let index = ReverseIndex::<U4, U4>::new();
+        // Explaining wiped cells from the wrong side lost some predecessors
+        // of this board.
+        let target = Board::parse("...#.\n....#\n#...#\n.....\n#....\n");
+        let with = predecessors(&target, &index, |_| {});
+        let without = predecessors(&target, &index, |state| state.learning = false);
+        assert_eq!(with, without);
+    }
+
+    #[test]
+    fn random_option_order_keeps_every_predecessor() {
+        let index = ReverseIndex::<U4, U4>::new();
+        let target = Board::parse("...#.\n....#\n#...#\n.....\n#....\n");
+        let ordered = predecessors(&target, &index, |_| {});
+        let random = predecessors(&target, &index, |state| state.config.randomize = true);
+        assert_eq!(ordered, random);
+    }
+
+    #[test]
+    fn tying_mirrors_keeps_every_symmetric_predecessor() {
+        let index = ReverseIndex::<U4, U4>::new();
+        for target in [
+            Board::parse("....\n.##.\n.##.\n....\n"),
+            Board::parse("....\n.#..\n..#.\n....\n"),
+            Board::parse("......\n.#..#.\n......\n"),
+        ] {
+            let symmetries = target.symmetries();
+            let tied = predecessors(&target, &index, |state| {
+                state.require_symmetries(&symmetries)
+            });
+            let filtered: MetroHashSet<_> = predecessors(&target, &index, |_| {})
+                .into_iter()
+                .filter(|board| symmetries.iter().all(|&s| board.is_symmetric(s)))
+                .collect();
+            assert!(!filtered.is_empty());
+            assert_eq!(tied, filtered);
+        }
+    }
+
+    #[test]
+    fn large_boards_advance_quickly() {
+        let index = ReverseIndex::<U4, U4>::new();
+        // A board reached six steps back from the default input. Propagating
+        // between cells with many options once took about 1 ms per step here.
+        let target = Board::parse(
+            "\
+             #........#..#.##.#.#..\n\
+             ..#.#....#............\n\
+             #.......######.#...#..\n\
+             ....#.#....#......#...\n\
+             #.#.......#....#..##..\n\
+             .....#..##..##.##.##..\n\
+             ...###...#.#.##.##....\n\
+             ....###.#.#...#..#.#..\n\
+             .#.....#.##..#..#.....\n\
+             #.##..#...#.#.#.#.#.#.\n\
+             ..##.....##...#.#..#..\n\
+             ..##..####.##...##.#..\n\
+             ..##.....#..###..##.#.\n\
+             .#.######....###......\n\
+             .....#.#.####.....##.#\n\
+             ......#..##...##.#....\n\
+             .......#...........##.\n\
+            ",
+        );
+        let mut state = State::new(&target, &index, SearchConfig::default(), 0);
+        let mut result = MetroHashSet::default();
+        let start = Instant::now();
+        state.advance(&mut result, 20_000);
+        assert!(
+            start.elapsed() < Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use smallvec::SmallVec;
+
+use crate::board::Board;
+
+/// The symmetries of the square, acting on a board about its centre.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symmetry {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    AntiTranspose,
+}
+
+impl Symmetry {
+    pub const ALL: [Symmetry; 8] = [
+        Symmetry::Identity,
+        Symmetry::Rotate90,
+        Symmetry::Rotate180,
+        Symmetry::Rotate270,
+        Symmetry::FlipHorizontal,
+        Symmetry::FlipVertical,
+        Symmetry::Transpose,
+        Symmetry::AntiTranspose,
+    ];
+    /// Whether the transformed board has its width and height swapped.
+    pub fn swaps_axes(self) -> bool {
+        matches!(
+            self,
+            Symmetry::Rotate90
+                | Symmetry::Rotate270
+                | Symmetry::Transpose
+                | Symmetry::AntiTranspose
+        )
+    }
+    /// Maps cell (`x`, `y`) of a `width` by `height` board to its position in
+    /// the transformed board.
+    pub fn apply(self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (rx, ry) = (width - 1 - x, height - 1 - y);
+        match self {
+            Symmetry::Identity => (x, y),
+            Symmetry::Rotate90 => (ry, x),
+            Symmetry::Rotate180 => (rx, ry),
+            Symmetry::Rotate270 => (y, rx),
+            Symmetry::FlipHorizontal => (rx, y),
+            Symmetry::FlipVertical => (x, ry),
+            Symmetry::Transpose => (y, x),
+            Symmetry::AntiTranspose => (ry, rx),
+        }
+    }
+}
+
+/// Restricts the predecessors of boards which are themselves symmetric.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymmetryMode {
+    /// Accept every predecessor.
+    #[default]
+    Any,
+    /// Predecessors of a board with 180 degree rotational symmetry must share
+    /// it.
+    C2,
+    /// Predecessors must have every symmetry of the board they lead to.
+    D4,
+}
+
+impl SymmetryMode {
+    /// The symmetries a predecessor of `target` is required to have.
+    pub fn required(self, target: &Board) -> SmallVec<[Symmetry; 8]> {
+        match self {
+            SymmetryMode::Any => SmallVec::new(),
+            SymmetryMode::C2 => target
+                .symmetries()
+                .into_iter()
+                .filter(|&symmetry| symmetry == Symmetry::Rotate180)
+                .collect(),
+            SymmetryMode::D4 => target.symmetries(),
+        }
+    }
+}
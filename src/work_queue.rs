@@ -1,300 +1,1033 @@
 use std::{
-    sync::{Arc, Condvar, Mutex},
-    thread,
-    time::Duration,
+    collections::BTreeMap,
+    hash::{Hash, Hasher},
+    mem,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use metrohash::MetroHashSet;
+use bitvec::vec::BitVec;
+use metrohash::{MetroHash64, MetroHashSet};
+use smallvec::SmallVec;
 
 use crate::{
-    BUDGET_FACTOR, board::Board, miniboard::MacroboardSize, reverse_index::ReverseIndex,
-    state::State,
+    affinity,
+    board::Board,
+    chain::ChainNode,
+    miniboard::{MacroDimension, MacroboardSize},
+    observer::{SearchObserver, print_chain},
+    progress::Progress,
+    reverse_index::ReverseIndex,
+    snapshot::Snapshot,
+    state::{SearchConfig, State},
+    symmetry::{Symmetry, SymmetryMode},
 };
 
-struct WorkItem<N: MacroboardSize> {
-    state: State<N>,
-    step: usize,
+/// An independent region of a work item's board, searched by its own
+/// [`State`].
+struct Part<W: MacroboardSize<H>, H: MacroDimension> {
+    state: State<W, H>,
+    /// Position of the part's region within the work item's board.
+    x: usize,
+    y: usize,
+    /// Untrimmed solutions found so far for this part.
+    found: Vec<Board>,
+}
+
+struct WorkItem<W: MacroboardSize<H>, H: MacroDimension> {
+    /// The board to find predecessors of, and the chain leading to it.
+    node: Arc<ChainNode>,
+    parts: Vec<Part<W, H>>,
     priority: isize,
+    /// Symmetries every predecessor of `node.board` must have.
+    required: SmallVec<[Symmetry; 8]>,
 }
 
 fn compute_priority(step: usize, live_count: usize, _size: usize, _score: usize) -> isize {
     (step as isize + 10) * 20 - (live_count as isize)
 }
 
-impl<N: MacroboardSize> WorkItem<N> {
-    fn new(board: Board, index: &ReverseIndex<N>, step: usize) -> Self {
-        let state = State::new(&board, index);
+/// Live cells further apart than this can be searched as separate parts
+/// without their predecessors interacting, given each part's margin.
+fn component_separation<W: MacroboardSize<H>, H: MacroDimension>() -> usize {
+    2 * component_margin::<W, H>() + 5
+}
+
+/// Dead cells kept around each part, so that its predecessors are not
+/// restricted to the immediate surroundings of its live cells.
+fn component_margin<W: MacroboardSize<H>, H: MacroDimension>() -> usize {
+    W::INT.max(H::INT) - 2
+}
+
+/// Regions whose `box_distance` is at most this can hold predecessors that
+/// interact. Each part's predecessors extend one cell past its region, so for
+/// regions `d` apart the nearest predecessor cells are `d - 2` apart, and a
+/// cell between them sees both when that is at most 2.
+const INTERACTION_DISTANCE: usize = 4;
+
+/// The difference between the nearest coordinates of two boxes, given as
+/// inclusive corners, along the axis where they are furthest apart. This is
+/// one more than the number of cells between them: adjacent boxes are 1
+/// apart, and overlapping boxes 0.
+fn box_distance(a: (usize, usize, usize, usize), b: (usize, usize, usize, usize)) -> usize {
+    let dx = b.0.saturating_sub(a.2).max(a.0.saturating_sub(b.2));
+    let dy = b.1.saturating_sub(a.3).max(a.1.saturating_sub(b.3));
+    dx.max(dy)
+}
+
+/// Merges regions, given as inclusive corners, until no two are within
+/// `INTERACTION_DISTANCE` of each other. A region can then neither contain
+/// live cells of another nor have predecessors that interact with another's.
+fn merge_regions(
+    mut regions: Vec<(usize, usize, usize, usize)>,
+) -> Vec<(usize, usize, usize, usize)> {
+    while let Some((i, j)) = (0..regions.len())
+        .flat_map(|j| (0..j).map(move |i| (i, j)))
+        .find(|&(i, j)| box_distance(regions[i], regions[j]) <= INTERACTION_DISTANCE)
+    {
+        let b = regions.remove(j);
+        let a = &mut regions[i];
+        *a = (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3));
+    }
+    regions
+}
+
+/// The region `(x, y, width, height)` of `board` centred on its live cells,
+/// as large as possible, which has the same symmetries about its centre as
+/// the live cells. Symmetric predecessors are centred on the live cells too,
+/// so none are lost by searching only this region. Returns `None` if the
+/// region would be too small to search.
+fn symmetric_region<W: MacroboardSize<H>, H: MacroDimension>(
+    board: &Board,
+    required: &[Symmetry],
+) -> Option<(usize, usize, usize, usize)> {
+    let (x0, y0, x1, y1) = board.bounding_box()?;
+    let mut margin_x = x0.min(board.width() - 1 - x1);
+    let mut margin_y = y0.min(board.height() - 1 - y1);
+    if required.iter().any(|symmetry| symmetry.swaps_axes()) {
+        margin_x = margin_x.min(margin_y);
+        margin_y = margin_x;
+    }
+    let width = x1 - x0 + 1 + 2 * margin_x;
+    let height = y1 - y0 + 1 + 2 * margin_y;
+    (width + 2 >= W::INT && height + 2 >= H::INT).then_some((
+        x0 - margin_x,
+        y0 - margin_y,
+        width,
+        height,
+    ))
+}
+
+/// Upper bound on the combined solutions produced for each new solution of a
+/// part, so that parts with many solutions cannot flood the queue.
+const MAX_COMBINATIONS: usize = 100;
+
+/// Steps of search given to an item each time it is advanced, scaled by the
+/// square of one more than its step.
+const BUDGET_FACTOR: usize = 10000;
+
+/// Steps taken between checks for termination while advancing an item, so
+/// that a shutdown does not wait for a whole budget to be used.
+const TERMINATION_CHECK_STEPS: usize = 10000;
+
+impl<W: MacroboardSize<H>, H: MacroDimension> WorkItem<W, H> {
+    fn new(node: Arc<ChainNode>, index: &ReverseIndex<W, H>, config: &WorkQueueConfig) -> Self {
+        let board = &node.board;
+        let mut regions = Vec::new();
+        if config.decompose {
+            let margin = component_margin::<W, H>();
+            let expanded = board
+                .components(component_separation::<W, H>())
+                .into_iter()
+                .map(|(x0, y0, x1, y1)| {
+                    (
+                        x0.saturating_sub(margin),
+                        y0.saturating_sub(margin),
+                        (x1 + margin).min(board.width() - 1),
+                        (y1 + margin).min(board.height() - 1),
+                    )
+                })
+                .collect();
+            let merged = merge_regions(expanded);
+            if merged.len() > 1 {
+                for (x0, y0, x1, y1) in merged {
+                    regions.push((x0, y0, x1 - x0 + 1, y1 - y0 + 1));
+                }
+            }
+        }
+        let required = config.symmetry.required(board);
+        if regions.is_empty() && !required.is_empty() {
+            regions.extend(symmetric_region::<W, H>(board, &required));
+        }
+        // Only a single symmetric region is searched for symmetric
+        // predecessors directly. Otherwise they are filtered in `combine`.
+        let symmetric = !required.is_empty() && regions.len() == 1;
+        if regions.is_empty() {
+            regions.push((0, 0, board.width(), board.height()));
+        }
+        let parts: Vec<_> = regions
+            .into_iter()
+            .map(|(x, y, width, height)| {
+                let region = board.crop(x as isize, y as isize, width, height);
+                let mut hasher = MetroHash64::with_seed(config.search.seed);
+                region.hash(&mut hasher);
+                let mut state = State::new(&region, index, config.search, hasher.finish());
+                if symmetric {
+                    state.require_symmetries(&required);
+                }
+                Part {
+                    state,
+                    x,
+                    y,
+                    found: Vec::new(),
+                }
+            })
+            .collect();
+        let score = parts.iter().map(|part| part.state.score()).sum::<usize>() / parts.len();
         Self {
-            priority: compute_priority(step, board.live_count(), board.size(), state.score(index)),
-            state,
-            step,
+            priority: compute_priority(node.step, board.live_count(), board.size(), score),
+            required,
+            parts,
+            node,
         }
     }
-    fn advance(&mut self, index: &ReverseIndex<N>, results: &mut MetroHashSet<Board>) {
-        if self.state.advance(
-            index,
-            results,
-            BUDGET_FACTOR * (self.step + 1) * (self.step + 1),
-        ) {
+    fn is_done(&self) -> bool {
+        self.parts.iter().all(|part| part.state.is_done())
+            || self
+                .parts
+                .iter()
+                .any(|part| part.state.is_done() && part.found.is_empty())
+    }
+    /// Combines `solution` for part `i` with every combination of solutions
+    /// found so far for the other parts.
+    fn combine(&self, i: usize, solution: &Board, results: &mut MetroHashSet<Board>) {
+        let mut combined = vec![Board::new(
+            BitVec::repeat(
+                false,
+                (self.node.board.width() + 2) * (self.node.board.height() + 2),
+            ),
+            self.node.board.width() + 2,
+        )];
+        for (j, part) in self.parts.iter().enumerate() {
+            let options = if j == i {
+                std::slice::from_ref(solution)
+            } else {
+                &part.found
+            };
+            combined = combined
+                .iter()
+                .flat_map(|board| {
+                    options.iter().map(move |option| {
+                        let mut board = board.clone();
+                        board.paste(option, part.x, part.y);
+                        board
+                    })
+                })
+                .take(MAX_COMBINATIONS)
+                .collect();
+        }
+        results.extend(
+            combined
+                .into_iter()
+                .filter(|board| {
+                    self.required
+                        .iter()
+                        .all(|&symmetry| board.is_symmetric(symmetry))
+                })
+                .map(|mut board| {
+                    board.trim();
+                    board
+                }),
+        );
+    }
+    /// Advances the search of each part, returning the number of steps taken.
+    /// Stops early once `terminated` is set, keeping what was found so far.
+    fn advance(&mut self, results: &mut MetroHashSet<Board>, terminated: &AtomicBool) -> u64 {
+        let mut nodes = 0;
+        let active = self
+            .parts
+            .iter()
+            .filter(|part| !part.state.is_done())
+            .count()
+            .max(1);
+        let budget = BUDGET_FACTOR * (self.node.step + 1) * (self.node.step + 1) / active;
+        let mut success = false;
+        for i in 0..self.parts.len() {
+            if self.parts[i].state.is_done() {
+                continue;
+            }
+            let mut solutions = MetroHashSet::default();
+            let before = self.parts[i].state.nodes();
+            let mut remaining = budget;
+            while remaining > 0
+                && !self.parts[i].state.is_done()
+                && !terminated.load(Ordering::SeqCst)
+            {
+                let steps = remaining.min(TERMINATION_CHECK_STEPS);
+                if self.parts[i].state.advance(&mut solutions, steps) {
+                    success = true;
+                }
+                remaining -= steps;
+            }
+            nodes += self.parts[i].state.nodes() - before;
+            for solution in solutions {
+                self.combine(i, &solution, results);
+                self.parts[i].found.push(solution);
+            }
+        }
+        if success {
             self.priority += 1;
         } else {
             self.priority -= 15;
         }
+        nodes
     }
 }
 
-impl<N: MacroboardSize> PartialOrd for WorkItem<N> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
+const MAX_LIST_LEN: usize = 1000;
+
+/// Work items for a single step, ordered by priority.
+///
+/// Ties are broken by insertion order so that the most recently added item is
+/// popped first, and the oldest is evicted first.
+struct StepQueue<W: MacroboardSize<H>, H: MacroDimension> {
+    items: BTreeMap<(isize, u64), WorkItem<W, H>>,
+    next_seq: u64,
 }
 
-impl<N: MacroboardSize> Ord for WorkItem<N> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        // We want to prioritize items with lower sunk cost
-        self.priority.cmp(&other.priority)
+impl<W: MacroboardSize<H>, H: MacroDimension> Default for StepQueue<W, H> {
+    fn default() -> Self {
+        Self {
+            items: BTreeMap::new(),
+            next_seq: 0,
+        }
     }
 }
 
-impl<N: MacroboardSize> Eq for WorkItem<N> {}
-impl<N: MacroboardSize> PartialEq for WorkItem<N> {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority
+impl<W: MacroboardSize<H>, H: MacroDimension> StepQueue<W, H> {
+    fn push(&mut self, item: WorkItem<W, H>) {
+        self.items.insert((item.priority, self.next_seq), item);
+        self.next_seq += 1;
+    }
+    fn pop(&mut self) -> Option<WorkItem<W, H>> {
+        self.items.pop_last().map(|(_, item)| item)
+    }
+    fn evict(&mut self) -> Option<WorkItem<W, H>> {
+        self.items.pop_first().map(|(_, item)| item)
+    }
+    fn peek_priority(&self) -> Option<isize> {
+        self.items
+            .last_key_value()
+            .map(|(&(priority, _), _)| priority)
+    }
+    fn lowest_priority(&self) -> Option<isize> {
+        self.items
+            .first_key_value()
+            .map(|(&(priority, _), _)| priority)
+    }
+    fn len(&self) -> usize {
+        self.items.len()
     }
 }
 
-const MAX_LIST_LEN: usize = 1000;
+struct PriorityQueue<W: MacroboardSize<H>, H: MacroDimension> {
+    items: Vec<StepQueue<W, H>>,
+}
 
-#[derive(Default)]
-struct PriorityQueue<N: MacroboardSize> {
-    items: Vec<Vec<WorkItem<N>>>,
+impl<W: MacroboardSize<H>, H: MacroDimension> Default for PriorityQueue<W, H> {
+    fn default() -> Self {
+        Self { items: Vec::new() }
+    }
 }
 
-impl<N: MacroboardSize> PriorityQueue<N> {
-    fn push(&mut self, item: WorkItem<N>) -> bool {
-        while self.items.len() <= item.step {
-            self.items.push(Vec::new());
-        }
-        let list = &mut self.items[item.step];
-        list.push(item);
-        list.sort();
-        if list.len() > MAX_LIST_LEN {
-            list.remove(0);
-            false
-        } else {
-            true
+impl<W: MacroboardSize<H>, H: MacroDimension> PriorityQueue<W, H> {
+    fn push(&mut self, item: WorkItem<W, H>) {
+        while self.items.len() <= item.node.step {
+            self.items.push(StepQueue::default());
         }
+        self.items[item.node.step].push(item);
+    }
+    /// The number of items queued for `step`.
+    fn len(&self, step: usize) -> usize {
+        self.items.get(step).map_or(0, |list| list.len())
     }
-    fn pop(&mut self) -> Option<WorkItem<N>> {
-        if let Some((_, idx)) = self
-            .items
+    fn lowest_priority(&self, step: usize) -> Option<isize> {
+        self.items.get(step)?.lowest_priority()
+    }
+    /// Removes the lowest priority item queued for `step`.
+    fn evict(&mut self, step: usize) -> Option<WorkItem<W, H>> {
+        self.items.get_mut(step)?.evict()
+    }
+    fn best(&self) -> Option<(isize, usize)> {
+        self.items
             .iter()
             .enumerate()
-            .filter_map(|(i, item)| {
-                item.last().map(|x| {
+            .filter_map(|(i, list)| {
+                list.peek_priority().map(|priority| {
                     (
-                        x.priority - self.items.get(i + 1).map(|v| v.len()).unwrap_or(0) as isize,
+                        priority - self.items.get(i + 1).map(|v| v.len()).unwrap_or(0) as isize,
                         i,
                     )
                 })
             })
             .max_by_key(|x| x.0)
-        {
-            self.items[idx].pop()
-        } else {
-            None
-        }
     }
-    fn is_empty(&self) -> bool {
-        self.items.iter().all(|list| list.is_empty())
+    fn peek_score(&self) -> Option<isize> {
+        self.best().map(|(score, _)| score)
+    }
+    fn pop(&mut self) -> Option<WorkItem<W, H>> {
+        let (_, idx) = self.best()?;
+        self.items[idx].pop()
+    }
+}
+
+/// A board found by the search, with the first chain found to reach it.
+struct Seen {
+    /// The canonical form of the node's board, kept when `canonicalize` is
+    /// set.
+    canonical: Option<Board>,
+    node: Arc<ChainNode>,
+}
+
+impl Seen {
+    /// The step and board that identify this entry. Boards compare and hash
+    /// by their live cells alone, so the node's own board serves as is.
+    fn key(&self) -> (usize, &Board) {
+        let board = self.canonical.as_ref().unwrap_or(&self.node.board);
+        (self.node.step, board)
+    }
+}
+
+impl PartialEq for Seen {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
     }
 }
 
-struct WorkQueueInner<N: MacroboardSize> {
-    heap: PriorityQueue<N>,
-    item_count: usize,
-    processed_count: usize,
-    terminated: bool,
+impl Eq for Seen {}
+
+impl Hash for Seen {
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        self.key().hash(state);
+    }
 }
 
 struct WorkQueueState {
-    seen_boards: MetroHashSet<(usize, Board)>,
+    /// Boards found at each step. Only one board of each orbit is kept when
+    /// `canonicalize` is set.
+    seen_boards: MetroHashSet<Seen>,
+    canonicalize: bool,
     completed_counts: Vec<usize>,
     best_step: usize,
+    best: Option<Arc<ChainNode>>,
 }
 
 impl WorkQueueState {
-    fn new() -> Self {
+    fn new(canonicalize: bool) -> Self {
         Self {
             seen_boards: MetroHashSet::default(),
+            canonicalize,
             completed_counts: Vec::new(),
             best_step: 0,
+            best: None,
         }
     }
-    fn observe(&mut self, step: usize, board: Board) -> bool {
-        if step > self.best_step {
-            self.best_step = step;
-            println!("{:?}", board);
-            let mut new_board = board.simulate();
-            for _ in 0..step {
-                println!("{:?}", new_board);
-                new_board = new_board.simulate();
-            }
-            println!("--------- {} ----------", step);
-            println!();
+    fn observe(&mut self, node: &Arc<ChainNode>) -> bool {
+        if node.step > self.best_step {
+            self.best_step = node.step;
+            self.best = Some(node.clone());
+        }
+        self.insert(node)
+    }
+    fn insert(&mut self, node: &Arc<ChainNode>) -> bool {
+        self.seen_boards.insert(Seen {
+            canonical: self.canonicalize.then(|| node.board.canonical()),
+            node: node.clone(),
+        })
+    }
+}
+
+/// Controls how many worker threads a [`WorkQueue`] runs and where.
+#[derive(Debug, Clone, Default)]
+pub struct WorkQueueConfig {
+    /// Explicit number of worker threads. Defaults to one per available core.
+    pub threads: Option<usize>,
+    /// Number of cores to leave free when `threads` is not set.
+    pub reserved_cores: usize,
+    /// Pin each worker thread to its own core.
+    pub pin_threads: bool,
+    /// Run a single worker so that results do not depend on thread
+    /// scheduling, making runs reproducible.
+    pub deterministic: bool,
+    /// Search well separated groups of live cells independently and combine
+    /// their predecessors. Each group's predecessors are confined to a margin
+    /// around it, so some predecessors of the whole board may be missed.
+    pub decompose: bool,
+    /// Keep a single board from each orbit under rotations and reflections,
+    /// so that symmetric copies of a board are not searched again.
+    pub canonicalize: bool,
+    pub symmetry: SymmetryMode,
+    pub search: SearchConfig,
+    /// How often [`WorkQueue::wait`] passes progress to
+    /// [`SearchObserver::on_stats`]. Progress is not reported if this is not
+    /// set.
+    pub progress_interval: Option<Duration>,
+}
+
+impl WorkQueueConfig {
+    pub fn num_threads(&self) -> usize {
+        if self.deterministic {
+            return 1;
         }
-        self.seen_boards.insert((step, board))
+        self.threads
+            .unwrap_or_else(|| num_cpus::get().saturating_sub(self.reserved_cores))
+            .max(1)
     }
 }
 
-pub struct WorkQueue<N: MacroboardSize> {
-    index: ReverseIndex<N>,
-    queue: Mutex<WorkQueueInner<N>>,
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long an idle worker sleeps before re-checking the other workers' queues.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+pub struct WorkQueue<W: MacroboardSize<H>, H: MacroDimension> {
+    index: ReverseIndex<W, H>,
+    /// One queue per worker. Workers push to their own queue and pop from it
+    /// unless another queue's best item scores higher.
+    shards: Vec<Mutex<PriorityQueue<W, H>>>,
+    /// Held while enforcing `MAX_LIST_LEN`, so that concurrent pushes do not
+    /// both evict for the same excess item.
+    evicting: Mutex<()>,
+    /// Boards queued in the snapshot being resumed which have not been made
+    /// into work items yet, last first. Each counts towards `item_count`.
+    resumed: Mutex<Vec<(usize, Board)>>,
+    item_count: AtomicUsize,
+    queued_count: AtomicUsize,
+    /// The number of items queued for each step up to the target across all
+    /// shards, so that a push only locks every shard once its step holds more
+    /// than `MAX_LIST_LEN`.
+    step_counts: Vec<AtomicUsize>,
+    processed_count: AtomicUsize,
+    node_count: AtomicU64,
+    terminated: AtomicBool,
+    idle: Mutex<()>,
     state: Mutex<WorkQueueState>,
+    /// The step of the last best passed to the observer, locked while it is
+    /// passed so that bests arrive in order.
+    reported_best: Mutex<usize>,
     condvar: Condvar,
     terminate_condvar: Condvar,
     target_step: usize,
+    config: WorkQueueConfig,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    observer: Arc<dyn SearchObserver>,
 }
 
-impl<N: MacroboardSize> WorkQueue<N> {
-    fn take_item(&self) -> Option<WorkItem<N>> {
-        let mut queue = self.queue.lock().unwrap();
-        while queue.heap.is_empty() && queue.item_count > 0 && !queue.terminated {
-            queue = self.condvar.wait(queue).unwrap();
+impl<W: MacroboardSize<H>, H: MacroDimension> WorkQueue<W, H> {
+    fn is_running(&self) -> bool {
+        self.item_count.load(Ordering::SeqCst) > 0 && !self.terminated.load(Ordering::SeqCst)
+    }
+    fn count_queued(&self, step: usize) {
+        self.queued_count.fetch_add(1, Ordering::SeqCst);
+        if let Some(count) = self.step_counts.get(step) {
+            count.fetch_add(1, Ordering::SeqCst);
         }
-        if queue.item_count == 0 || queue.terminated {
-            return None;
+    }
+    fn count_taken(&self, step: usize) {
+        self.queued_count.fetch_sub(1, Ordering::SeqCst);
+        if let Some(count) = self.step_counts.get(step) {
+            count.fetch_sub(1, Ordering::SeqCst);
         }
-        queue.heap.pop()
     }
-    fn add_item(&self, item: WorkItem<N>) {
-        let mut queue = self.queue.lock().unwrap();
-        if queue.heap.push(item) {
-            queue.item_count += 1;
+    /// Pops the best item of the worker's own shard, or of another shard if
+    /// its best item scores higher, so that step priorities hold across
+    /// shards. Other shards are only peeked at if they are not locked, so a
+    /// busy shard cannot hold up the worker.
+    fn pop_best(&self, worker: usize) -> Option<WorkItem<W, H>> {
+        let local = self.shards[worker].lock().unwrap().peek_score();
+        let remote = (0..self.shards.len())
+            .filter(|&i| i != worker)
+            .filter_map(|i| {
+                let shard = self.shards[i].try_lock().ok()?;
+                shard.peek_score().map(|score| (score, i))
+            })
+            .max_by_key(|x| x.0);
+        let shard = match (local, remote) {
+            (Some(local), Some((score, i))) if score > local => i,
+            (Some(_), _) => worker,
+            (None, Some((_, i))) => i,
+            (None, None) => return None,
+        };
+        let item = self.shards[shard].lock().unwrap().pop()?;
+        self.count_taken(item.node.step);
+        Some(item)
+    }
+    fn take_item(&self, worker: usize) -> Option<WorkItem<W, H>> {
+        while self.is_running() {
+            if let Some(item) = self.pop_best(worker) {
+                return Some(item);
+            }
+            let idle = self.idle.lock().unwrap();
+            if self.queued_count.load(Ordering::SeqCst) == 0 && self.is_running() {
+                drop(self.condvar.wait_timeout(idle, IDLE_TIMEOUT).unwrap());
+            }
         }
+        None
+    }
+    fn add_item(&self, worker: usize, item: WorkItem<W, H>) {
+        let step = item.node.step;
+        self.shards[worker].lock().unwrap().push(item);
+        self.item_count.fetch_add(1, Ordering::SeqCst);
+        self.count_queued(step);
+        self.enforce_limit(step);
+        let _idle = self.idle.lock().unwrap();
         self.condvar.notify_one();
     }
+    /// Evicts the lowest priority item queued for `step` from whichever shard
+    /// holds it once the shards together hold more than `MAX_LIST_LEN`, so
+    /// that the bound does not depend on the number of workers. Steps beyond
+    /// the target, which only a resumed snapshot can hold, are not counted in
+    /// `step_counts` and are always checked.
+    fn enforce_limit(&self, step: usize) {
+        let within = |count: &AtomicUsize| count.load(Ordering::SeqCst) <= MAX_LIST_LEN;
+        if self.step_counts.get(step).is_some_and(within) {
+            return;
+        }
+        let _evicting = self.evicting.lock().unwrap();
+        let len: usize = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len(step))
+            .sum();
+        if len <= MAX_LIST_LEN {
+            return;
+        }
+        let victim = (0..self.shards.len())
+            .filter_map(|i| Some((self.shards[i].lock().unwrap().lowest_priority(step)?, i)))
+            .min_by_key(|x| x.0);
+        if let Some((_, i)) = victim
+            && self.shards[i].lock().unwrap().evict(step).is_some()
+        {
+            self.item_count.fetch_sub(1, Ordering::SeqCst);
+            self.count_taken(step);
+        }
+    }
+    /// Makes work items of the resumed boards until none are left or the
+    /// search is terminated. The workers do this rather than `start`, so that
+    /// a shutdown while resuming a large snapshot is not held up, and the
+    /// boards not reached stay queued in the next snapshot.
+    fn build_resumed(&self, worker: usize) {
+        while !self.terminated.load(Ordering::SeqCst) {
+            let Some((step, board)) = self.resumed.lock().unwrap().pop() else {
+                break;
+            };
+            let item = WorkItem::new(ChainNode::root(board, step), &self.index, &self.config);
+            self.add_item(worker, item);
+            // The item was counted when the snapshot was loaded.
+            self.item_count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    /// Passes a new best to the observer, unless a later one has already been
+    /// passed by another worker.
+    fn report_best(&self, node: &ChainNode) {
+        let mut reported = self.reported_best.lock().unwrap();
+        if node.step > *reported {
+            *reported = node.step;
+            self.observer.on_new_best(node.step, &node.chain());
+        }
+    }
     fn record_completed(&self, step: usize) {
         let mut state = self.state.lock().unwrap();
         if step >= state.completed_counts.len() {
             state.completed_counts.resize(step + 1, 0);
         }
         state.completed_counts[step] += 1;
+        drop(state);
+        self.observer.on_item_completed(step);
     }
     fn complete_item(&self) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.processed_count += 1;
-        if queue.item_count > 0 {
-            queue.item_count -= 1;
-            if queue.item_count == 0 {
-                queue.terminated = true;
-                self.condvar.notify_all();
-                self.terminate_condvar.notify_all();
-            }
+        self.processed_count.fetch_add(1, Ordering::SeqCst);
+        if self.item_count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.terminate();
         }
     }
     fn terminate(&self) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.terminated = true;
+        self.terminated.store(true, Ordering::SeqCst);
+        let _idle = self.idle.lock().unwrap();
         self.condvar.notify_all();
         self.terminate_condvar.notify_all();
     }
-    fn run(&self) {
-        while let Some(mut item) = self.take_item() {
+    fn run(&self, worker: usize) {
+        self.build_resumed(worker);
+        while let Some(mut item) = self.take_item(worker) {
             let mut results = MetroHashSet::default();
-            item.advance(&self.index, &mut results);
+            let nodes = item.advance(&mut results, &self.terminated);
+            self.node_count.fetch_add(nodes, Ordering::Relaxed);
+            // Hash set iteration order is not meaningful, so sort to keep the
+            // order in which results are observed and queued reproducible.
+            let mut results: Vec<_> = results.into_iter().collect();
+            results.sort();
+            let step = item.node.step + 1;
+            let mut results: Vec<_> = results
+                .into_iter()
+                .map(|board| ChainNode::child(&item.node, board))
+                .collect();
             if !results.is_empty() {
                 let mut state = self.state.lock().unwrap();
-                results.retain(|board| state.observe(item.step + 1, board.clone()));
+                let best_step = state.best_step;
+                results.retain(|node| state.observe(node));
+                let best = state.best.clone().filter(|_| state.best_step > best_step);
+                drop(state);
+                if let Some(node) = best {
+                    self.report_best(&node);
+                }
 
-                if item.step + 1 == self.target_step {
+                for result in &results {
+                    self.observer.on_solution(step, &result.board);
+                }
+
+                if step == self.target_step {
                     self.terminate();
                     return;
                 }
             }
 
             for result in results {
-                self.add_item(WorkItem::new(result.clone(), &self.index, item.step + 1));
+                self.add_item(worker, WorkItem::new(result, &self.index, &self.config));
             }
 
-            if item.state.is_done() {
-                self.record_completed(item.step);
+            if item.is_done() {
+                self.record_completed(item.node.step);
             } else {
-                self.add_item(item);
+                self.add_item(worker, item);
             }
             self.complete_item();
         }
     }
-    pub fn start(initial_boards: Vec<Board>, num_steps: usize, parallel: bool) -> Arc<Self> {
-        let queue = Arc::new(WorkQueue::<N> {
-            index: ReverseIndex::<N>::compute(),
-            queue: Mutex::new(WorkQueueInner {
-                heap: PriorityQueue::default(),
-                item_count: 0,
-                processed_count: 0,
-                terminated: false,
-            }),
-            state: Mutex::new(WorkQueueState::new()),
+    pub fn start(
+        snapshot: Snapshot,
+        num_steps: usize,
+        config: &WorkQueueConfig,
+        observer: Arc<dyn SearchObserver>,
+    ) -> Arc<Self> {
+        let num_threads = config.num_threads();
+        let mut resumed = snapshot.queued;
+        resumed.reverse();
+        let queue = Arc::new(WorkQueue::<W, H> {
+            index: ReverseIndex::<W, H>::new(),
+            shards: (0..num_threads)
+                .map(|_| Mutex::new(PriorityQueue::default()))
+                .collect(),
+            evicting: Mutex::new(()),
+            item_count: AtomicUsize::new(resumed.len()),
+            resumed: Mutex::new(resumed),
+            queued_count: AtomicUsize::new(0),
+            step_counts: (0..=num_steps).map(|_| AtomicUsize::new(0)).collect(),
+            processed_count: AtomicUsize::new(0),
+            node_count: AtomicU64::new(0),
+            terminated: AtomicBool::new(false),
+            idle: Mutex::new(()),
+            state: Mutex::new(WorkQueueState::new(config.canonicalize)),
+            reported_best: Mutex::new(0),
             condvar: Condvar::new(),
             terminate_condvar: Condvar::new(),
             target_step: num_steps,
+            config: config.clone(),
+            workers: Mutex::new(Vec::new()),
+            observer,
         });
 
-        for board in initial_boards {
-            queue.add_item(WorkItem::new(board, &queue.index, 0));
+        {
+            let mut state = queue.state.lock().unwrap();
+            for (step, board) in snapshot.seen {
+                state.observe(&ChainNode::root(board, step));
+            }
         }
 
-        if parallel {
-            for _ in 0..num_cpus::get() {
-                let queue2 = queue.clone();
-                thread::spawn(move || {
-                    queue2.run();
-                });
-            }
-        } else {
+        let mut workers = queue.workers.lock().unwrap();
+        for worker in 0..num_threads {
             let queue2 = queue.clone();
-            thread::spawn(move || {
-                queue2.run();
-            });
+            let pin = config.pin_threads;
+            workers.push(thread::spawn(move || {
+                if pin && !affinity::pin_current_thread(worker) {
+                    eprintln!("Failed to pin worker {} to a core", worker);
+                }
+                queue2.run(worker);
+            }));
         }
+        drop(workers);
         queue
     }
-    pub fn wait(&self) {
-        let mut queue = self.queue.lock().unwrap();
-        while queue.item_count > 0 && !queue.terminated {
-            queue = self
-                .terminate_condvar
-                .wait_timeout(queue, Duration::from_secs(5))
-                .unwrap()
-                .0;
+    /// Blocks until the search finishes or `shutdown` is set, then stops the
+    /// workers and waits for them to finish their current item.
+    pub fn wait(&self, shutdown: &AtomicBool) {
+        let mut last_stats = Instant::now();
+        while self.is_running() {
+            if shutdown.load(Ordering::SeqCst) {
+                self.terminate();
+                continue;
+            }
             {
-                let state = self.state.lock().unwrap();
-                let mut counts = vec![0; state.best_step + 1];
-                for (step, _) in &state.seen_boards {
-                    counts[*step] += 1;
+                let idle = self.idle.lock().unwrap();
+                if self.is_running() {
+                    drop(
+                        self.terminate_condvar
+                            .wait_timeout(idle, SHUTDOWN_POLL_INTERVAL)
+                            .unwrap(),
+                    );
                 }
-                let queue_counts = queue
-                    .heap
-                    .items
-                    .iter()
-                    .map(|list| list.len())
-                    .collect::<Vec<_>>();
-                let priorities = queue
-                    .heap
-                    .items
-                    .iter()
-                    .map(|list| list.last().map_or(0, |item| item.priority))
-                    .collect::<Vec<_>>();
-                println!(
-                    "{} active items... ({} processed) \n    Queue: {:?}\n    Priorities: {:?}\n    Found: {:?}\n    Complete: {:?}\n",
-                    queue.item_count,
-                    queue.processed_count,
-                    queue_counts,
-                    priorities,
-                    counts,
-                    state.completed_counts
+            }
+            if let Some(interval) = self.config.progress_interval
+                && last_stats.elapsed() >= interval
+            {
+                last_stats = Instant::now();
+                self.observer.on_stats(&self.progress());
+            }
+        }
+        self.observer.on_finish();
+        let workers = mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            worker.join().expect("Worker thread panicked");
+        }
+    }
+    fn progress(&self) -> Progress {
+        let mut queued_by_step = Vec::new();
+        let mut priorities = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            if shard.items.len() > queued_by_step.len() {
+                queued_by_step.resize(shard.items.len(), 0);
+                priorities.resize(shard.items.len(), None);
+            }
+            for (i, list) in shard.items.iter().enumerate() {
+                queued_by_step[i] += list.len();
+                priorities[i] = priorities[i].max(list.peek_priority());
+            }
+        }
+        let state = self.state.lock().unwrap();
+        let mut found = vec![0; state.best_step + 1];
+        for seen in &state.seen_boards {
+            found[seen.node.step] += 1;
+        }
+        Progress {
+            nodes: self.node_count.load(Ordering::Relaxed),
+            processed: self.processed_count.load(Ordering::SeqCst),
+            active: self.item_count.load(Ordering::SeqCst),
+            queued: self.queued_count.load(Ordering::SeqCst),
+            queued_by_step,
+            priorities,
+            completed: state.completed_counts.clone(),
+            best_step: state.best_step,
+            target_step: self.target_step,
+            found,
+            index_bytes: self.index.stats().bytes,
+        }
+    }
+    pub fn print_best(&self) {
+        let state = self.state.lock().unwrap();
+        match &state.best {
+            Some(node) => {
+                println!("Best chain found:");
+                print_chain(&node.chain());
+            }
+            None => println!("No predecessors found"),
+        }
+    }
+    /// Captures the queued and discovered boards. Should only be called once
+    /// `wait` has returned.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut queued: Vec<_> = self.resumed.lock().unwrap().iter().rev().cloned().collect();
+        for shard in &self.shards {
+            for list in &shard.lock().unwrap().items {
+                queued.extend(
+                    list.items
+                        .values()
+                        .map(|item| (item.node.step, item.node.board.clone())),
                 );
             }
         }
+        let mut seen: Vec<_> = self
+            .state
+            .lock()
+            .unwrap()
+            .seen_boards
+            .iter()
+            .map(|seen| {
+                let (step, board) = seen.key();
+                (step, board.tight())
+            })
+            .collect();
+        seen.sort();
+        Snapshot { queued, seen }
+    }
+    /// The distinct boards that the chains found so far end with.
+    pub fn targets(&self) -> Vec<Board> {
+        let state = self.state.lock().unwrap();
+        let targets: MetroHashSet<Board> = state
+            .seen_boards
+            .iter()
+            .map(|seen| Board::clone(seen.node.target()))
+            .collect();
+        let mut targets: Vec<_> = targets.into_iter().collect();
+        targets.sort();
+        targets
+    }
+    /// The chain of every board found so far whose chain ends with `target`,
+    /// from the longest.
+    pub fn chains_to(&self, target: &Board) -> Vec<Vec<Board>> {
+        let state = self.state.lock().unwrap();
+        let mut nodes: Vec<_> = state
+            .seen_boards
+            .iter()
+            .map(|seen| &seen.node)
+            .filter(|node| **node.target() == *target)
+            .collect();
+        nodes.sort_by_key(|node| (std::cmp::Reverse(node.step), node.board.clone()));
+        nodes.into_iter().map(|node| node.chain()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typenum::U4;
+
+    use super::*;
+    use crate::rng::Rng;
+
+    const ROUNDS: usize = 200_000;
+
+    /// The queue `StepQueue` replaced: a `Vec` sorted by priority after every
+    /// push.
+    struct SortedVecQueue<W: MacroboardSize<H>, H: MacroDimension> {
+        items: Vec<WorkItem<W, H>>,
+    }
+
+    impl<W: MacroboardSize<H>, H: MacroDimension> SortedVecQueue<W, H> {
+        fn push(&mut self, item: WorkItem<W, H>) -> Option<WorkItem<W, H>> {
+            self.items.push(item);
+            self.items.sort_by_key(|item| item.priority);
+            (self.items.len() > MAX_LIST_LEN).then(|| self.items.remove(0))
+        }
+        fn pop(&mut self) -> Option<WorkItem<W, H>> {
+            self.items.pop()
+        }
+    }
+
+    /// Pops and re-pushes items of a full queue with new priorities, pushing a
+    /// new item every other time so that the oldest ones are evicted, as the
+    /// workers do.
+    fn churn<Q>(
+        queue: &mut Q,
+        mut spare: Vec<WorkItem<U4, U4>>,
+        push: impl Fn(&mut Q, WorkItem<U4, U4>) -> Option<WorkItem<U4, U4>>,
+        pop: impl Fn(&mut Q) -> Option<WorkItem<U4, U4>>,
+    ) -> Duration {
+        let mut rng = Rng::new(0);
+        let start = Instant::now();
+        for i in 0..ROUNDS {
+            let mut item = pop(queue).unwrap();
+            item.priority = rng.below(1000) as isize;
+            spare.extend(push(queue, item));
+            if i % 2 == 0 {
+                let mut item = spare.pop().unwrap();
+                item.priority = rng.below(1000) as isize;
+                spare.extend(push(queue, item));
+            }
+        }
+        start.elapsed()
+    }
+
+    fn items(count: usize) -> Vec<WorkItem<U4, U4>> {
+        let index = ReverseIndex::<U4, U4>::new();
+        let config = WorkQueueConfig::default();
+        let mut rng = Rng::new(1);
+        (0..count)
+            .map(|_| {
+                let board = Board::parse("#.\n.#\n");
+                let mut item = WorkItem::new(ChainNode::root(board, 0), &index, &config);
+                item.priority = rng.below(1000) as isize;
+                item
+            })
+            .collect()
+    }
+
+    /// Compares `StepQueue` with the sorted `Vec` it replaced. Run with
+    /// `cargo test --release -- --ignored --nocapture step_queue_benchmark`.
+    #[test]
+    #[ignore]
+    fn step_queue_benchmark() {
+        let mut sorted = SortedVecQueue { items: Vec::new() };
+        let mut spare = items(MAX_LIST_LEN + 1);
+        for item in spare.drain(1..) {
+            sorted.push(item);
+        }
+        let sorted_time = churn(
+            &mut sorted,
+            spare,
+            SortedVecQueue::push,
+            SortedVecQueue::pop,
+        );
+
+        let mut step_queue = StepQueue::default();
+        let mut spare = items(MAX_LIST_LEN + 1);
+        for item in spare.drain(1..) {
+            step_queue.push(item);
+        }
+        let step_queue_time = churn(
+            &mut step_queue,
+            spare,
+            |queue, item| {
+                queue.push(item);
+                (queue.len() > MAX_LIST_LEN).then(|| queue.evict().unwrap())
+            },
+            StepQueue::pop,
+        );
+        eprintln!(
+            "{} rounds on a queue of {}: sorted Vec {:?}, StepQueue {:?}",
+            ROUNDS, MAX_LIST_LEN, sorted_time, step_queue_time
+        );
+    }
+
+    fn board_with(cells: &[(usize, usize)]) -> Board {
+        let width = cells.iter().map(|&(x, _)| x + 1).max().unwrap();
+        let height = cells.iter().map(|&(_, y)| y + 1).max().unwrap();
+        let mut bits = BitVec::repeat(false, width * height);
+        for &(x, y) in cells {
+            bits.set(y * width + x, true);
+        }
+        Board::new(bits, width)
+    }
+
+    #[test]
+    fn decomposed_results_reach_the_target() {
+        let index = ReverseIndex::<U4, U4>::new();
+        let config = WorkQueueConfig {
+            decompose: true,
+            ..Default::default()
+        };
+        let targets: [(&[(usize, usize)], usize); 2] = [
+            // Two blinkers far enough apart to be searched separately.
+            (&[(0, 0), (1, 0), (2, 0), (20, 0), (20, 1), (20, 2)], 2),
+            // A cell inside the bounding box of a spread out group, which
+            // must not be searched separately from it.
+            (&[(0, 0), (8, 0), (16, 0), (16, 8), (16, 16), (7, 9)], 1),
+        ];
+        for (cells, parts) in targets {
+            let target = board_with(cells);
+            let mut item = WorkItem::new(ChainNode::root(target.clone(), 0), &index, &config);
+            assert_eq!(item.parts.len(), parts);
+            let mut results = MetroHashSet::default();
+            while results.len() < 1000 && !item.is_done() {
+                item.advance(&mut results, &AtomicBool::new(false));
+            }
+            assert!(!results.is_empty());
+            for result in results {
+                assert_eq!(result.simulate(), target, "{:?}", result);
+            }
+        }
     }
 }